@@ -14,15 +14,59 @@
  * limitations under the License.
  */
 
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::io::{Cursor, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+
 use anyhow::{Result, anyhow};
-use clap::{ArgGroup, Parser};
+use apache_avro::types::Value as AvroValue;
+use apache_avro::{Schema, from_avro_datum};
+use clap::{ArgGroup, Args, Parser, ValueEnum};
 use hex_slice::AsHex;
-use rocksdb::{DB, IteratorMode, Options};
+use parquet::data_type::{ByteArray, ByteArrayType};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::parser::parse_message_type;
+use reqwest::{Certificate, Identity};
+use serde_json::{Map, Value};
 
-use kafka_rocksdb::logging::setup_logger;
+use kafka_rocksdb::logging::setup_stderr_logger;
+use kafka_rocksdb::reader::Reader;
 use schema_registry_converter::blocking::avro::AvroDecoder;
 use schema_registry_converter::blocking::schema_registry::SrSettings;
 
+const PARQUET_ROW_GROUP_SIZE: usize = 100_000;
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    /// `key: value` lines per column family
+    Lines,
+    /// One JSON object per line
+    Jsonl,
+    Csv,
+    Parquet,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Column {
+    ColumnFamily,
+    Key,
+    Value,
+}
+
+impl Column {
+    fn name(&self) -> &'static str {
+        match self {
+            Column::ColumnFamily => "column_family",
+            Column::Key => "key",
+            Column::Value => "value",
+        }
+    }
+}
+
 #[derive(Parser, Debug)]
 #[clap(author, about, version, group = ArgGroup::new("output"))]
 struct CommandLineOptions {
@@ -30,8 +74,49 @@ struct CommandLineOptions {
     output_binary: bool,
     #[clap(group = "output", name = "text", long, short, help = "Output as Text")]
     output_text: bool,
-    #[clap(group = "output", name = "avro", long, short, help = "Output as Avro")]
+    #[clap(
+        group = "output",
+        name = "avro",
+        long,
+        short,
+        value_name = "schema registry url",
+        help = "Output as Avro"
+    )]
     output_avro: Option<String>,
+    #[clap(
+        group = "output",
+        long,
+        value_name = "directory",
+        help = "Output as Avro using <schema id>.avsc files instead of a Schema Registry"
+    )]
+    avro_schemas: Option<PathBuf>,
+    #[clap(flatten)]
+    schema_registry: SchemaRegistryOptions,
+    #[clap(long, short, value_enum, default_value_t = Format::Lines, help = "Output format")]
+    format: Format,
+    #[clap(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_value = "column-family,key,value",
+        help = "Columns of jsonl, csv and parquet output"
+    )]
+    columns: Vec<Column>,
+    #[clap(
+        long = "column-family",
+        value_name = "column family",
+        help = "Only dump the column families of these topics"
+    )]
+    column_families: Vec<String>,
+    #[clap(long, help = "Only dump keys starting with this text")]
+    prefix: Option<String>,
+    #[clap(
+        long,
+        short = 'O',
+        value_name = "file",
+        help = "Write to file instead of stdout"
+    )]
+    output_file: Option<String>,
     #[clap(
         value_name = "database directory",
         help = "RocksDB database directory",
@@ -40,47 +125,364 @@ struct CommandLineOptions {
     db_directory: String,
 }
 
-impl CommandLineOptions {
-    fn output(&self, data: &[u8]) -> Result<String> {
-        if self.output_text {
-            Ok(String::from_utf8_lossy(data).to_string())
-        } else if let Some(ref url) = self.output_avro {
-            let sr_settings = SrSettings::new(url.clone());
-            let decoder = AvroDecoder::new(sr_settings);
-            let result = decoder.decode(Some(data)).map_err(|e| anyhow!("{e}"))?;
-            Ok(format!("{:?}", result.value))
-        } else {
-            Ok(format!("{:02X}", data.plain_hex(false)))
+#[derive(Args, Debug)]
+struct SchemaRegistryOptions {
+    #[clap(long, help = "Schema Registry basic auth user")]
+    sr_username: Option<String>,
+    #[clap(long, help = "Schema Registry basic auth password")]
+    sr_password: Option<String>,
+    #[clap(long, help = "Schema Registry bearer token")]
+    sr_token: Option<String>,
+    #[clap(
+        long,
+        value_name = "pem file",
+        help = "CA certificate of the Schema Registry"
+    )]
+    sr_ca_certificate: Option<PathBuf>,
+    #[clap(
+        long,
+        value_name = "pem file",
+        help = "Client certificate and key for the Schema Registry"
+    )]
+    sr_client_identity: Option<PathBuf>,
+    #[clap(long, help = "Don't verify the certificate of the Schema Registry")]
+    sr_insecure: bool,
+}
+
+impl SchemaRegistryOptions {
+    fn settings(&self, url: &str) -> Result<SrSettings> {
+        let mut builder = SrSettings::new_builder(url.to_string());
+        if let Some(ref username) = self.sr_username {
+            builder.set_basic_authorization(username, self.sr_password.as_deref());
+        }
+        if let Some(ref token) = self.sr_token {
+            builder.set_token_authorization(token);
+        }
+        let mut client = reqwest::blocking::Client::builder();
+        if let Some(ref path) = self.sr_ca_certificate {
+            client = client.add_root_certificate(Certificate::from_pem(&std::fs::read(path)?)?);
         }
+        if let Some(ref path) = self.sr_client_identity {
+            client = client.identity(Identity::from_pem(&std::fs::read(path)?)?);
+        }
+        if self.sr_insecure {
+            client = client.danger_accept_invalid_certs(true);
+        }
+        builder.build_with(client).map_err(|e| anyhow!("{e}"))
     }
 }
 
-fn list_db(options: CommandLineOptions) -> Result<()> {
-    let db_options = Options::default();
-    let cfs = DB::list_cf(&db_options, &options.db_directory)?;
-    let db = DB::open_cf_as_secondary(
-        &db_options,
-        &options.db_directory,
-        &options.db_directory,
-        &cfs,
-    )?;
-    db.try_catch_up_with_primary()?;
-    for cf in cfs {
-        println!("ColumnFamily: {}", &cf);
-        let cfh = db.cf_handle(&cf).unwrap();
-        for row in db.iterator_cf(cfh, IteratorMode::Start) {
-            match row {
-                Ok((k, v)) => println!("{}: {}", options.output(&k)?, options.output(&v)?),
-                Err(e) => println!("Failed to read row: {e}"),
+/// Avro schemas read from `<schema id>.avsc` files, cached by id. Missing or invalid schemas
+/// are cached as well, so every row using them reports the error on stderr without reading the
+/// file again.
+struct SchemaDirectory {
+    directory: PathBuf,
+    schemas: RefCell<HashMap<u32, Result<Arc<Schema>, String>>>,
+}
+
+impl SchemaDirectory {
+    fn schema(&self, id: u32) -> Result<Arc<Schema>> {
+        let mut schemas = self.schemas.borrow_mut();
+        let schema = schemas.entry(id).or_insert_with(|| {
+            let path = self.directory.join(format!("{id}.avsc"));
+            std::fs::read_to_string(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|schema| Ok(Arc::new(Schema::parse_str(&schema)?)))
+                .map_err(|e| format!("Schema {}: {e}", path.display()))
+        });
+        schema.clone().map_err(|e| anyhow!(e))
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<AvroValue> {
+        match data {
+            [0, a, b, c, d, datum @ ..] => {
+                let schema = self.schema(u32::from_be_bytes([*a, *b, *c, *d]))?;
+                Ok(from_avro_datum(&schema, &mut Cursor::new(datum), None)?)
+            }
+            _ => Err(anyhow!("Invalid Avro data: {:02X}", data.plain_hex(false))),
+        }
+    }
+}
+
+/// Renders keys and values. Decoders and schemas are created once and reused for all rows.
+enum Decoder {
+    Hex,
+    Text,
+    Avro(AvroDecoder),
+    AvroSchemas(SchemaDirectory),
+}
+
+impl Decoder {
+    fn new(options: &CommandLineOptions) -> Result<Decoder> {
+        if options.output_text {
+            Ok(Decoder::Text)
+        } else if let Some(ref url) = options.output_avro {
+            Ok(Decoder::Avro(AvroDecoder::new(
+                options.schema_registry.settings(url)?,
+            )))
+        } else if let Some(ref directory) = options.avro_schemas {
+            Ok(Decoder::AvroSchemas(SchemaDirectory {
+                directory: directory.clone(),
+                schemas: RefCell::new(HashMap::new()),
+            }))
+        } else {
+            Ok(Decoder::Hex)
+        }
+    }
+
+    /// Decodes `data`, falling back to hex after reporting the error, so that one undecodable
+    /// row doesn't abort the dump.
+    fn decode_or_hex(&self, column_family: &str, what: &str, data: &[u8]) -> Value {
+        self.decode(data).unwrap_or_else(|e| {
+            log::error!(
+                "Failed to decode {what} {:02X} in {column_family}: {e:#}",
+                data.plain_hex(false)
+            );
+            Decoder::Hex.decode(data).unwrap_or(Value::Null)
+        })
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<Value> {
+        match self {
+            Decoder::Hex => Ok(Value::String(format!("{:02X}", data.plain_hex(false)))),
+            Decoder::Text => Ok(Value::String(String::from_utf8_lossy(data).to_string())),
+            Decoder::Avro(decoder) => {
+                let result = decoder.decode(Some(data)).map_err(|e| anyhow!("{e}"))?;
+                Ok(Value::try_from(result.value)?)
             }
+            Decoder::AvroSchemas(schemas) => Ok(Value::try_from(schemas.decode(data)?)?),
+        }
+    }
+}
+
+struct Record {
+    column_family: String,
+    key: Value,
+    value: Value,
+}
+
+impl Record {
+    fn json(&self, column: Column) -> Value {
+        match column {
+            Column::ColumnFamily => Value::String(self.column_family.clone()),
+            Column::Key => self.key.clone(),
+            Column::Value => self.value.clone(),
+        }
+    }
+
+    fn text(&self, column: Column) -> String {
+        match self.json(column) {
+            Value::String(s) => s,
+            v => v.to_string(),
         }
     }
-    Ok(())
+}
+
+trait RecordWriter {
+    fn write(&mut self, record: Record) -> Result<()>;
+
+    fn finish(self: Box<Self>) -> Result<()>;
+}
+
+struct LinesWriter {
+    out: Box<dyn Write>,
+    column_family: Option<String>,
+}
+
+impl RecordWriter for LinesWriter {
+    fn write(&mut self, record: Record) -> Result<()> {
+        if self.column_family.as_ref() != Some(&record.column_family) {
+            writeln!(self.out, "ColumnFamily: {}", record.column_family)?;
+            self.column_family = Some(record.column_family.clone());
+        }
+        writeln!(
+            self.out,
+            "{}: {}",
+            record.text(Column::Key),
+            record.text(Column::Value)
+        )?;
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+struct JsonLinesWriter {
+    out: Box<dyn Write>,
+    columns: Vec<Column>,
+}
+
+impl RecordWriter for JsonLinesWriter {
+    fn write(&mut self, record: Record) -> Result<()> {
+        let object: Map<String, Value> = self
+            .columns
+            .iter()
+            .map(|c| (c.name().to_string(), record.json(*c)))
+            .collect();
+        serde_json::to_writer(&mut self.out, &object)?;
+        writeln!(self.out)?;
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+struct CsvWriter {
+    out: csv::Writer<Box<dyn Write>>,
+    columns: Vec<Column>,
+}
+
+impl RecordWriter for CsvWriter {
+    fn write(&mut self, record: Record) -> Result<()> {
+        self.out
+            .write_record(self.columns.iter().map(|c| record.text(*c)))?;
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+struct ParquetWriter {
+    out: SerializedFileWriter<File>,
+    columns: Vec<Column>,
+    rows: Vec<Vec<ByteArray>>,
+}
+
+impl ParquetWriter {
+    fn new(file: File, columns: Vec<Column>) -> Result<ParquetWriter> {
+        let fields: String = columns
+            .iter()
+            .map(|c| format!("required binary {} (UTF8);", c.name()))
+            .collect();
+        let schema = Arc::new(parse_message_type(&format!(
+            "message record {{ {fields} }}"
+        ))?);
+        let properties = Arc::new(WriterProperties::builder().build());
+        Ok(ParquetWriter {
+            out: SerializedFileWriter::new(file, schema, properties)?,
+            rows: vec![vec![]; columns.len()],
+            columns,
+        })
+    }
+
+    fn write_row_group(&mut self) -> Result<()> {
+        if self.rows[0].is_empty() {
+            return Ok(());
+        }
+        let mut row_group = self.out.next_row_group()?;
+        let mut values = self.rows.iter_mut();
+        while let Some(mut column) = row_group.next_column()? {
+            let values = values
+                .next()
+                .ok_or_else(|| anyhow!("Unexpected parquet column"))?;
+            column
+                .typed::<ByteArrayType>()
+                .write_batch(values, None, None)?;
+            column.close()?;
+            values.clear();
+        }
+        row_group.close()?;
+        Ok(())
+    }
+}
+
+impl RecordWriter for ParquetWriter {
+    fn write(&mut self, record: Record) -> Result<()> {
+        for (column, values) in self.columns.iter().zip(self.rows.iter_mut()) {
+            values.push(ByteArray::from(record.text(*column).as_str()));
+        }
+        if self.rows[0].len() >= PARQUET_ROW_GROUP_SIZE {
+            self.write_row_group()?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        self.write_row_group()?;
+        self.out.close()?;
+        Ok(())
+    }
+}
+
+fn record_writer(options: &CommandLineOptions) -> Result<Box<dyn RecordWriter>> {
+    if options.columns.is_empty() {
+        return Err(anyhow!("At least one column is required"));
+    }
+    let columns = options.columns.clone();
+    Ok(match options.format {
+        Format::Lines => Box::new(LinesWriter {
+            out: output(options)?,
+            column_family: None,
+        }),
+        Format::Jsonl => Box::new(JsonLinesWriter {
+            out: output(options)?,
+            columns,
+        }),
+        Format::Csv => {
+            let mut out = csv::Writer::from_writer(output(options)?);
+            out.write_record(columns.iter().map(|c| c.name()))?;
+            Box::new(CsvWriter { out, columns })
+        }
+        Format::Parquet => {
+            let file = match options.output_file {
+                Some(ref file) => File::create(file)?,
+                None => return Err(anyhow!("Parquet output requires --output-file")),
+            };
+            Box::new(ParquetWriter::new(file, columns)?)
+        }
+    })
+}
+
+fn output(options: &CommandLineOptions) -> Result<Box<dyn Write>> {
+    Ok(match options.output_file {
+        Some(ref file) => Box::new(std::io::BufWriter::new(File::create(file)?)),
+        None => Box::new(std::io::BufWriter::new(std::io::stdout())),
+    })
+}
+
+fn dump(options: &CommandLineOptions, reader: &Reader) -> Result<()> {
+    let decoder = Decoder::new(options)?;
+    let mut writer = record_writer(options)?;
+    let filter: BTreeSet<&String> = options.column_families.iter().collect();
+    let prefix = options.prefix.clone().unwrap_or_default();
+    for topic in reader.topics()? {
+        if !filter.is_empty() && !filter.contains(&topic) {
+            continue;
+        }
+        reader.for_each(&topic, &prefix, |k, v| {
+            writer.write(Record {
+                column_family: topic.clone(),
+                key: decoder.decode_or_hex(&topic, "key", k),
+                value: decoder.decode_or_hex(&topic, "value", v),
+            })?;
+            Ok(true)
+        })?;
+    }
+    writer.finish()
+}
+
+fn list_db(options: CommandLineOptions) -> Result<()> {
+    let secondary = std::env::temp_dir().join(format!("dump_db-{}", std::process::id()));
+    let result = Reader::open(&options.db_directory, &secondary, None)
+        .and_then(|reader| dump(&options, &reader));
+    if secondary.exists() {
+        std::fs::remove_dir_all(secondary)?;
+    }
+    result
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let opts = CommandLineOptions::parse();
-    setup_logger()?;
+    setup_stderr_logger()?;
     Ok(list_db(opts)?)
 }
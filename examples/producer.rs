@@ -14,18 +14,94 @@
  * limitations under the License.
  */
 
+//! Produces generated messages, e.g. to benchmark kafka-rocksdb.
+
+use std::str::FromStr;
+use std::time::Instant;
+
 use anyhow::{Result, anyhow};
+use apache_avro::Schema;
 use apache_avro::types::Value;
 use clap::{ArgGroup, Parser};
+use futures::StreamExt;
+use futures::stream::FuturesUnordered;
+use rand::distr::{Alphanumeric, SampleString};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use rdkafka::ClientConfig;
 use rdkafka::producer::{FutureProducer, FutureRecord};
-use schema_registry_converter::blocking::avro::AvroEncoder;
-use schema_registry_converter::blocking::schema_registry::SrSettings;
-use schema_registry_converter::schema_registry_common::SubjectNameStrategy;
+use schema_registry_converter::avro_common::get_supplied_schema;
+use schema_registry_converter::blocking::schema_registry::{SrSettings, post_schema};
 use tokio::time::Duration;
 
 use kafka_rocksdb::logging::setup_logger;
 
+const MAX_IN_FLIGHT: usize = 10_000;
+const QUEUE_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_SCHEMA: &str =
+    r#"{"type": "record", "name": "test", "fields": [{"name": "key", "type": "string"}]}"#;
+
+/// Distribution of value sizes: `<size>`, `uniform:<min>-<max>` or `normal:<mean>,<stddev>`.
+#[derive(Debug, Clone, Copy)]
+enum SizeDistribution {
+    Fixed(usize),
+    Uniform(usize, usize),
+    Normal(f64, f64),
+}
+
+impl FromStr for SizeDistribution {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(range) = s.strip_prefix("uniform:") {
+            let (min, max) = range
+                .split_once('-')
+                .ok_or_else(|| anyhow!("Expected uniform:<min>-<max>"))?;
+            let (min, max) = (min.parse()?, max.parse()?);
+            if min > max {
+                return Err(anyhow!("Expected min <= max"));
+            }
+            Ok(SizeDistribution::Uniform(min, max))
+        } else if let Some(params) = s.strip_prefix("normal:") {
+            let (mean, stddev) = params
+                .split_once(',')
+                .ok_or_else(|| anyhow!("Expected normal:<mean>,<stddev>"))?;
+            Ok(SizeDistribution::Normal(mean.parse()?, stddev.parse()?))
+        } else {
+            Ok(SizeDistribution::Fixed(s.parse()?))
+        }
+    }
+}
+
+impl SizeDistribution {
+    fn sample(&self, rng: &mut impl Rng) -> usize {
+        match *self {
+            SizeDistribution::Fixed(size) => size,
+            SizeDistribution::Uniform(min, max) => rng.random_range(min..=max),
+            SizeDistribution::Normal(mean, stddev) => {
+                // Box-Muller transform
+                let (u1, u2): (f64, f64) = (1.0 - rng.random::<f64>(), rng.random());
+                let z = (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos();
+                (mean + z * stddev).round().max(0.0) as usize
+            }
+        }
+    }
+}
+
+fn parse_rate(s: &str) -> Result<f64> {
+    match s.parse::<f64>()? {
+        rate if rate.is_finite() && rate > 0.0 => Ok(rate),
+        _ => Err(anyhow!("Expected a positive rate")),
+    }
+}
+
+fn parse_ratio(s: &str) -> Result<f64> {
+    match s.parse::<f64>()? {
+        ratio if (0.0..=1.0).contains(&ratio) => Ok(ratio),
+        _ => Err(anyhow!("Expected a ratio from 0 to 1")),
+    }
+}
+
 #[derive(Parser, Debug)]
 #[clap(author, about, version, group = ArgGroup::new("output"))]
 struct CommandLineOptions {
@@ -39,38 +115,194 @@ struct CommandLineOptions {
     output_text: bool,
     #[clap(group = "output", name = "avro", long, help = "Output as Avro")]
     output_avro: Option<String>,
+    #[clap(long, short = 'n', default_value_t = 1, help = "Number of messages")]
+    messages: u64,
+    #[clap(
+        long,
+        short = 'r',
+        value_parser = parse_rate,
+        help = "Messages per second (default: unlimited)"
+    )]
+    rate: Option<f64>,
+    #[clap(long, help = "Number of distinct keys (default: --messages)")]
+    key_cardinality: Option<u64>,
+    #[clap(
+        long,
+        default_value = "3",
+        help = "Value size: <size>, uniform:<min>-<max> or normal:<mean>,<stddev>"
+    )]
+    value_size: SizeDistribution,
+    #[clap(
+        long,
+        default_value_t = 0.0,
+        value_parser = parse_ratio,
+        help = "Ratio of tombstones (null values)"
+    )]
+    tombstone_ratio: f64,
+    #[clap(
+        long,
+        value_name = "avsc file",
+        requires = "avro",
+        help = "Avro schema of keys"
+    )]
+    key_schema: Option<String>,
+    #[clap(
+        long,
+        value_name = "avsc file",
+        requires = "avro",
+        help = "Avro schema of values"
+    )]
+    value_schema: Option<String>,
 }
 
-impl CommandLineOptions {
-    fn register_schemas(&self, url: &str) -> Result<()> {
-        let client = reqwest::blocking::Client::new();
-        for kv in vec!["key", "value"] {
-            client
-                .post(&format!("{}/subjects/{}-{}/versions", url, self.kafka_topic, kv))
-                .header("Content-Type", "application/vnd.schemaregistry.v1+json")
-                .body(r#"{"schema": "{\"type\": \"record\", \"name\": \"test\", \"fields\": [{\"name\": \"key\", \"type\": \"string\"}]}"}"#)
-                .send()?;
-        }
-        Ok(())
+/// An Avro schema registered once for the key or value subject of the topic.
+struct RegisteredSchema {
+    id: u32,
+    schema: Schema,
+}
+
+impl RegisteredSchema {
+    fn register(sr_settings: &SrSettings, subject: String, file: Option<&str>) -> Result<Self> {
+        let schema = match file {
+            Some(file) => Schema::parse_str(&std::fs::read_to_string(file)?)?,
+            None => Schema::parse_str(DEFAULT_SCHEMA)?,
+        };
+        let registered = post_schema(sr_settings, subject, get_supplied_schema(&schema))
+            .map_err(|e| anyhow!("{e}"))?;
+        Ok(RegisteredSchema {
+            id: registered.id,
+            schema,
+        })
+    }
+
+    fn encode(&self, value: Value) -> Result<Vec<u8>> {
+        let mut bytes = vec![0];
+        bytes.extend_from_slice(&self.id.to_be_bytes());
+        bytes.extend(apache_avro::to_avro_datum(&self.schema, value)?);
+        Ok(bytes)
     }
+}
+
+enum Generator {
+    Binary,
+    Text,
+    Avro {
+        key: RegisteredSchema,
+        value: RegisteredSchema,
+    },
+}
 
-    async fn output(&self, topic: &str, key: bool) -> Result<Vec<u8>> {
-        if self.output_text {
-            Ok("test".as_bytes().into())
-        } else if let Some(ref url) = self.output_avro {
-            self.register_schemas(url)?;
+fn random_string(rng: &mut impl Rng, size: usize) -> String {
+    Alphanumeric.sample_string(rng, size)
+}
+
+fn random_bytes(rng: &mut impl Rng, size: usize) -> Vec<u8> {
+    let mut bytes = vec![0; size];
+    rng.fill(&mut bytes[..]);
+    bytes
+}
+
+/// Generates a value for `schema`, using `size` for the length of strings, bytes and collections.
+fn random_value(schema: &Schema, rng: &mut impl Rng, size: &SizeDistribution) -> Result<Value> {
+    Ok(match schema {
+        Schema::Null => Value::Null,
+        Schema::Boolean => Value::Boolean(rng.random()),
+        Schema::Int => Value::Int(rng.random()),
+        Schema::Long => Value::Long(rng.random()),
+        Schema::Float => Value::Float(rng.random()),
+        Schema::Double => Value::Double(rng.random()),
+        Schema::Bytes => {
+            let size = size.sample(rng);
+            Value::Bytes(random_bytes(rng, size))
+        }
+        Schema::String => {
+            let size = size.sample(rng);
+            Value::String(random_string(rng, size))
+        }
+        Schema::Array(array) => Value::Array(
+            (0..rng.random_range(0..=4))
+                .map(|_| random_value(&array.items, rng, size))
+                .collect::<Result<_>>()?,
+        ),
+        Schema::Map(map) => Value::Map(
+            (0..rng.random_range(0..=4))
+                .map(|_| Ok((random_string(rng, 8), random_value(&map.types, rng, size)?)))
+                .collect::<Result<_>>()?,
+        ),
+        Schema::Union(union) => {
+            let index = rng.random_range(0..union.variants().len());
+            Value::Union(
+                index as u32,
+                Box::new(random_value(&union.variants()[index], rng, size)?),
+            )
+        }
+        Schema::Record(record) => Value::Record(
+            record
+                .fields
+                .iter()
+                .map(|f| Ok((f.name.clone(), random_value(&f.schema, rng, size)?)))
+                .collect::<Result<_>>()?,
+        ),
+        Schema::Enum(e) => {
+            let index = rng.random_range(0..e.symbols.len());
+            Value::Enum(index as u32, e.symbols[index].clone())
+        }
+        Schema::Fixed(fixed) => Value::Fixed(fixed.size, random_bytes(rng, fixed.size)),
+        schema => return Err(anyhow!("Unsupported schema: {schema:?}")),
+    })
+}
+
+impl Generator {
+    fn new(opts: &CommandLineOptions) -> Result<Generator> {
+        if opts.output_text {
+            Ok(Generator::Text)
+        } else if let Some(ref url) = opts.output_avro {
             let sr_settings = SrSettings::new(url.clone());
-            let encoder = AvroEncoder::new(sr_settings);
-            let strategy = SubjectNameStrategy::TopicNameStrategy(String::from(topic), key);
-            let bytes = encoder
-                .encode(
-                    vec![("key", Value::String(String::from("value")))],
-                    &strategy,
-                )
-                .map_err(|e| anyhow!("{e}"))?;
-            Ok(bytes)
+            let subject = |kv| format!("{}-{kv}", opts.kafka_topic);
+            Ok(Generator::Avro {
+                key: RegisteredSchema::register(
+                    &sr_settings,
+                    subject("key"),
+                    opts.key_schema.as_deref(),
+                )?,
+                value: RegisteredSchema::register(
+                    &sr_settings,
+                    subject("value"),
+                    opts.value_schema.as_deref(),
+                )?,
+            })
         } else {
-            Ok(vec![1, 2, 3])
+            Ok(Generator::Binary)
+        }
+    }
+
+    /// The key with index `index`, which is the same for every call.
+    fn key(&self, index: u64) -> Result<Vec<u8>> {
+        match self {
+            Generator::Binary => Ok(index.to_be_bytes().to_vec()),
+            Generator::Text => Ok(format!("key-{index}").into_bytes()),
+            Generator::Avro { key, .. } => {
+                let mut rng = StdRng::seed_from_u64(index);
+                key.encode(random_value(
+                    &key.schema,
+                    &mut rng,
+                    &SizeDistribution::Fixed(16),
+                )?)
+            }
+        }
+    }
+
+    fn value(&self, rng: &mut impl Rng, size: &SizeDistribution) -> Result<Vec<u8>> {
+        match self {
+            Generator::Binary => {
+                let size = size.sample(rng);
+                Ok(random_bytes(rng, size))
+            }
+            Generator::Text => {
+                let size = size.sample(rng);
+                Ok(random_string(rng, size).into_bytes())
+            }
+            Generator::Avro { value, .. } => value.encode(random_value(&value.schema, rng, size)?),
         }
     }
 }
@@ -79,15 +311,51 @@ async fn produce(opts: CommandLineOptions) -> Result<()> {
     let producer: FutureProducer = ClientConfig::new()
         .set("bootstrap.servers", &opts.kafka_bootstrap_servers)
         .create()?;
-    let key = opts.output(&opts.kafka_topic, true).await?;
-    let value = opts.output(&opts.kafka_topic, false).await?;
-    let record = FutureRecord::to(&opts.kafka_topic)
-        .key(&key)
-        .payload(&value);
-    producer
-        .send(record, Duration::from_secs(5))
-        .await
-        .map_err(|e| e.0)?;
+    let generator = Generator::new(&opts)?;
+    let key_cardinality = opts.key_cardinality.unwrap_or(opts.messages).max(1);
+    let mut rng = StdRng::from_os_rng();
+    let mut in_flight = FuturesUnordered::new();
+    let mut errors = 0;
+    let start = Instant::now();
+    for i in 0..opts.messages {
+        if let Some(rate) = opts.rate {
+            let due = start + Duration::from_secs_f64(i as f64 / rate);
+            tokio::time::sleep_until(due.into()).await;
+        }
+        while in_flight.len() >= MAX_IN_FLIGHT {
+            if let Some(Err(_)) = in_flight.next().await {
+                errors += 1;
+            }
+        }
+        let key = generator.key(rng.random_range(0..key_cardinality))?;
+        let value = match rng.random_bool(opts.tombstone_ratio) {
+            true => None,
+            false => Some(generator.value(&mut rng, &opts.value_size)?),
+        };
+        let producer = &producer;
+        let topic = opts.kafka_topic.as_str();
+        in_flight.push(async move {
+            let mut record = FutureRecord::<Vec<u8>, Vec<u8>>::to(topic).key(&key);
+            if let Some(ref value) = value {
+                record = record.payload(value);
+            }
+            producer
+                .send(record, QUEUE_TIMEOUT)
+                .await
+                .map_err(|(e, _)| log::error!("Failed to produce message: {e}"))
+        });
+    }
+    while let Some(result) = in_flight.next().await {
+        if result.is_err() {
+            errors += 1;
+        }
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    log::info!(
+        "Produced {} messages ({errors} failed) in {elapsed:.1}s: {:.0} messages/s",
+        opts.messages,
+        opts.messages as f64 / elapsed
+    );
     Ok(())
 }
 
@@ -0,0 +1,71 @@
+/*
+ * Copyright 2021 Michael Krolikowski
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde::Serialize;
+
+use crate::backup::Backups;
+use crate::rebuild::Rebuilds;
+
+pub async fn blocking<T, F>(f: F) -> Response
+where
+    T: Serialize + Send + 'static,
+    F: FnOnce() -> Result<T> + Send + 'static,
+{
+    match tokio::task::spawn_blocking(f).await {
+        Ok(Ok(result)) => Json(result).into_response(),
+        Ok(Err(e)) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+async fn list_backups(State(backups): State<Arc<Backups>>) -> Response {
+    blocking(move || backups.list()).await
+}
+
+async fn create_backup(State(backups): State<Arc<Backups>>) -> Response {
+    blocking(move || backups.create()).await
+}
+
+async fn rebuild(State(rebuilds): State<Arc<Rebuilds>>, Path(topic): Path<String>) -> Response {
+    match rebuilds.spawn(&topic) {
+        Ok(()) => StatusCode::ACCEPTED.into_response(),
+        Err(e) => (StatusCode::CONFLICT, e.to_string()).into_response(),
+    }
+}
+
+pub fn router(backups: Option<Arc<Backups>>, rebuilds: Arc<Rebuilds>) -> Router {
+    let mut router = Router::new().merge(
+        Router::new()
+            .route("/admin/rebuild/{topic}", post(rebuild))
+            .with_state(rebuilds),
+    );
+    if let Some(backups) = backups {
+        router = router.merge(
+            Router::new()
+                .route("/admin/backups", get(list_backups).post(create_backup))
+                .with_state(backups),
+        );
+    }
+    router
+}
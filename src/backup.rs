@@ -0,0 +1,190 @@
+/*
+ * Copyright 2021 Michael Krolikowski
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use prometheus::IntCounter;
+use rocksdb::Env;
+use rocksdb::backup::{BackupEngine, BackupEngineOptions};
+use serde::Serialize;
+
+use crate::database::Database;
+use crate::settings::{BackupMode, BackupSettings};
+
+pub const CHECKPOINT_PREFIX: &str = "checkpoint-";
+
+#[derive(Debug, Serialize)]
+pub struct BackupInfo {
+    pub id: String,
+    pub timestamp: i64,
+    pub size: u64,
+}
+
+pub struct Backups {
+    db: Arc<Database>,
+    config: BackupSettings,
+    lock: Mutex<()>,
+    created: IntCounter,
+}
+
+fn directory_size(path: &Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(path)? {
+        let metadata = entry?.metadata()?;
+        if metadata.is_file() {
+            size += metadata.len();
+        }
+    }
+    Ok(size)
+}
+
+/// Lists the checkpoints in `directory`, oldest first.
+pub fn list_checkpoints(directory: &Path) -> Result<Vec<(BackupInfo, PathBuf)>> {
+    let mut checkpoints = vec![];
+    if !directory.exists() {
+        return Ok(checkpoints);
+    }
+    for entry in std::fs::read_dir(directory)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if let Some(Ok(millis)) = name.strip_prefix(CHECKPOINT_PREFIX).map(str::parse::<i64>) {
+            let info = BackupInfo {
+                id: name,
+                timestamp: millis / 1000,
+                size: directory_size(&entry.path())?,
+            };
+            checkpoints.push((millis, info, entry.path()));
+        }
+    }
+    // Checkpoints created within the same second are ordered by their milliseconds.
+    checkpoints.sort_by_key(|(millis, _, _)| *millis);
+    Ok(checkpoints
+        .into_iter()
+        .map(|(_, info, path)| (info, path))
+        .collect())
+}
+
+pub fn open_backup_engine(directory: &Path) -> Result<BackupEngine> {
+    let options = BackupEngineOptions::new(directory)?;
+    Ok(BackupEngine::open(&options, &Env::new()?)?)
+}
+
+impl Backups {
+    pub fn new(db: Arc<Database>, config: &BackupSettings, pipeline: &str) -> Backups {
+        Backups {
+            db,
+            config: config.clone(),
+            lock: Mutex::new(()),
+            created: crate::metrics::BACKUPS.with_label_values(&[pipeline]),
+        }
+    }
+
+    fn directory(&self) -> &Path {
+        Path::new(&self.config.directory)
+    }
+
+    pub fn create(&self) -> Result<BackupInfo> {
+        let _lock = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        std::fs::create_dir_all(self.directory())?;
+        let info = match self.config.mode {
+            BackupMode::Checkpoint => self.create_checkpoint()?,
+            BackupMode::Backup => self.create_backup()?,
+        };
+        self.created.inc();
+        Ok(info)
+    }
+
+    fn create_checkpoint(&self) -> Result<BackupInfo> {
+        let now = chrono::Utc::now().timestamp_millis();
+        let id = format!("{CHECKPOINT_PREFIX}{now}");
+        let path = self.directory().join(&id);
+        self.db.create_checkpoint(&path)?;
+        let info = BackupInfo {
+            id,
+            timestamp: now / 1000,
+            size: directory_size(&path)?,
+        };
+        let checkpoints = list_checkpoints(self.directory())?;
+        let obsolete = checkpoints
+            .len()
+            .saturating_sub(self.config.retention.get());
+        for (info, path) in checkpoints.into_iter().take(obsolete) {
+            log::info!("Removing checkpoint {}", info.id);
+            std::fs::remove_dir_all(path)?;
+        }
+        Ok(info)
+    }
+
+    fn create_backup(&self) -> Result<BackupInfo> {
+        let mut engine = open_backup_engine(self.directory())?;
+        self.db.create_backup(&mut engine)?;
+        engine.purge_old_backups(self.config.retention.get())?;
+        engine
+            .get_backup_info()
+            .into_iter()
+            .max_by_key(|info| info.backup_id)
+            .map(|info| BackupInfo {
+                id: info.backup_id.to_string(),
+                timestamp: info.timestamp,
+                size: info.size,
+            })
+            .ok_or_else(|| anyhow::anyhow!("Backup not found after creating it"))
+    }
+
+    pub fn list(&self) -> Result<Vec<BackupInfo>> {
+        match self.config.mode {
+            BackupMode::Checkpoint => Ok(list_checkpoints(self.directory())?
+                .into_iter()
+                .map(|(info, _)| info)
+                .collect()),
+            BackupMode::Backup => {
+                if !self.directory().exists() {
+                    return Ok(vec![]);
+                }
+                let engine = open_backup_engine(self.directory())?;
+                Ok(engine
+                    .get_backup_info()
+                    .into_iter()
+                    .map(|info| BackupInfo {
+                        id: info.backup_id.to_string(),
+                        timestamp: info.timestamp,
+                        size: info.size,
+                    })
+                    .collect())
+            }
+        }
+    }
+
+    pub async fn schedule(self: Arc<Self>) -> Result<()> {
+        let interval = match self.config.interval {
+            Some(interval) => interval,
+            None => return futures::future::pending().await,
+        };
+        let mut interval = tokio::time::interval(Duration::from_secs(interval));
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let backups = self.clone();
+            match tokio::task::spawn_blocking(move || backups.create()).await? {
+                Ok(info) => log::info!("Created backup {}", info.id),
+                Err(e) => log::error!("Failed to create backup: {e}"),
+            }
+        }
+    }
+}
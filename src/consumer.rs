@@ -14,44 +14,222 @@
  * limitations under the License.
  */
 
-use anyhow::Result;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
 use rdkafka::ClientConfig;
 use rdkafka::config::RDKafkaLogLevel;
-use rdkafka::consumer::{Consumer, DefaultConsumerContext, MessageStream, StreamConsumer};
+use rdkafka::consumer::{Consumer, MessageStream, StreamConsumer};
+use rdkafka::{Offset, TopicPartitionList};
+
+use crate::database::Database;
+use crate::kafka_context::KafkaRocksDBContext;
+use crate::kafka_stream_ext::EndOffsets;
+use crate::offsets::Offsets;
+use crate::settings::{ConsumerMode, ConsumerSettings, Settings, StartPosition};
 
-use crate::settings::Settings;
+const METADATA_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub type KafkaStreamConsumer = StreamConsumer<KafkaRocksDBContext>;
 
 pub struct KafkaConsumer {
-    consumer: StreamConsumer,
+    consumer: KafkaStreamConsumer,
+    topics: RwLock<Vec<String>>,
 }
 
-fn kafka_client_config(config: &Settings) -> ClientConfig {
+pub fn kafka_client_config(config: &Settings) -> ClientConfig {
     let mut client_config = ClientConfig::default();
     client_config.set_log_level(RDKafkaLogLevel::Debug);
-    client_config.set("auto.offset.reset", "earliest");
-    client_config.set("enable.auto.commit", "true");
+    let auto_offset_reset = match config.consumer.start {
+        StartPosition::Latest => "latest",
+        _ => "earliest",
+    };
+    client_config.set("auto.offset.reset", auto_offset_reset);
+    let auto_commit = config.consumer.mode == ConsumerMode::Group;
+    client_config.set("enable.auto.commit", auto_commit.to_string());
     client_config.set("enable.auto.offset.store", "false");
+    if config.consumer.until_end {
+        client_config.set("enable.partition.eof", "true");
+    }
+    if !config.librdkafka.debug.is_empty() {
+        client_config.set("debug", config.librdkafka.debug.join(","));
+    }
     for (k, v) in config.kafka.iter() {
         client_config.set(k, v);
     }
     client_config
 }
 
+/// Start offsets of `partitions`: the `stored` offsets, or the configured start positions.
+pub fn start_positions(
+    consumer: &impl Consumer<KafkaRocksDBContext>,
+    config: &ConsumerSettings,
+    stored: &Offsets,
+    partitions: &TopicPartitionList,
+) -> Result<TopicPartitionList> {
+    let mut assignment = TopicPartitionList::new();
+    let mut timestamps = TopicPartitionList::new();
+    for elem in partitions.elements() {
+        let (topic, partition) = (elem.topic(), elem.partition());
+        let position = match stored.get(topic, partition) {
+            Some(offset) => StartPosition::Offset(offset),
+            None => config.start_position(topic, partition),
+        };
+        match position {
+            StartPosition::Earliest => {
+                assignment.add_partition_offset(topic, partition, Offset::Beginning)?
+            }
+            StartPosition::Latest => {
+                assignment.add_partition_offset(topic, partition, Offset::End)?
+            }
+            StartPosition::Offset(offset) => {
+                assignment.add_partition_offset(topic, partition, Offset::Offset(offset))?
+            }
+            StartPosition::Timestamp(timestamp) => {
+                timestamps.add_partition_offset(topic, partition, Offset::Offset(timestamp))?
+            }
+        }
+    }
+    if timestamps.count() > 0 {
+        let offsets = consumer.offsets_for_times(timestamps, METADATA_TIMEOUT)?;
+        for elem in offsets.elements() {
+            elem.error().with_context(|| {
+                format!(
+                    "Failed to look up the start offset of {}/{} by timestamp",
+                    elem.topic(),
+                    elem.partition()
+                )
+            })?;
+            assignment.add_partition_offset(elem.topic(), elem.partition(), elem.offset())?;
+        }
+    }
+    Ok(assignment)
+}
+
 impl KafkaConsumer {
-    pub fn new(config: &Settings) -> Result<KafkaConsumer> {
-        let consumer: StreamConsumer<DefaultConsumerContext> =
-            kafka_client_config(config).create()?;
-        let topics: Vec<&str> = config.topics.iter().map(|s| s.as_str()).collect();
-        consumer.subscribe(&topics)?;
-        Ok(KafkaConsumer { consumer })
+    /// Creates a consumer for the configured topics, starting from the offsets stored in the
+    /// database or the configured start positions.
+    ///
+    /// In group mode the topics are subscribed to and assigned partitions are positioned on
+    /// every rebalance. In assign mode all partitions are assigned.
+    pub fn new(config: &Settings, db: &Arc<Database>) -> Result<KafkaConsumer> {
+        let context = KafkaRocksDBContext::new(&config.librdkafka);
+        let context = match config.consumer.mode {
+            ConsumerMode::Group => context.resuming_from(db.clone(), &config.consumer),
+            ConsumerMode::Assign => context,
+        };
+        let consumer: KafkaStreamConsumer =
+            kafka_client_config(config).create_with_context(context)?;
+        match config.consumer.mode {
+            ConsumerMode::Group => {
+                let topics: Vec<&str> = config.topics.iter().map(|s| s.as_str()).collect();
+                consumer.subscribe(&topics)?;
+            }
+            ConsumerMode::Assign => {
+                let assignment =
+                    KafkaConsumer::assignment(&consumer, config, &config.topics, &db.offsets()?)?;
+                log::info!("Assigning {assignment:?}");
+                consumer.assign(&assignment)?;
+            }
+        }
+        Ok(KafkaConsumer {
+            consumer,
+            topics: RwLock::new(config.topics.clone()),
+        })
+    }
+
+    fn assignment(
+        consumer: &KafkaStreamConsumer,
+        config: &Settings,
+        topics: &[String],
+        stored: &Offsets,
+    ) -> Result<TopicPartitionList> {
+        let mut partitions = TopicPartitionList::new();
+        for topic in topics.iter() {
+            let metadata = consumer.fetch_metadata(Some(topic.as_str()), METADATA_TIMEOUT)?;
+            for partition in metadata
+                .topics()
+                .iter()
+                .filter(|t| t.name() == topic)
+                .flat_map(|t| t.partitions())
+            {
+                partitions.add_partition(topic, partition.id());
+            }
+        }
+        start_positions(consumer, &config.consumer, stored, &partitions)
+    }
+
+    /// Returns the current high watermarks of all partitions, starting from the `stored` offsets.
+    pub fn end_offsets(&self, stored: &Offsets) -> Result<EndOffsets> {
+        let mut end = EndOffsets::default();
+        let topics = self
+            .topics
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        for topic in topics.iter() {
+            let metadata = self
+                .consumer
+                .fetch_metadata(Some(topic.as_str()), METADATA_TIMEOUT)?;
+            for partition in metadata
+                .topics()
+                .iter()
+                .filter(|t| t.name() == topic)
+                .flat_map(|t| t.partitions())
+            {
+                let (low, high) =
+                    self.consumer
+                        .fetch_watermarks(topic, partition.id(), METADATA_TIMEOUT)?;
+                let position = stored.get(topic, partition.id()).unwrap_or(0).max(low);
+                end.insert(topic, partition.id(), position, high);
+            }
+        }
+        Ok(end)
+    }
+
+    /// Switches to the topics of `config`, starting to consume the `added` ones and stopping
+    /// to consume the `removed` ones.
+    pub fn update_topics(
+        &self,
+        config: &Settings,
+        stored: &Offsets,
+        added: &[String],
+        removed: &[String],
+    ) -> Result<()> {
+        match config.consumer.mode {
+            ConsumerMode::Group => {
+                let topics: Vec<&str> = config.topics.iter().map(|s| s.as_str()).collect();
+                self.consumer.subscribe(&topics)?;
+            }
+            ConsumerMode::Assign => {
+                let mut revoked = TopicPartitionList::new();
+                for elem in self.consumer.assignment()?.elements() {
+                    if removed.iter().any(|t| t == elem.topic()) {
+                        revoked.add_partition(elem.topic(), elem.partition());
+                    }
+                }
+                if revoked.count() > 0 {
+                    log::info!("Unassigning {revoked:?}");
+                    self.consumer.incremental_unassign(&revoked)?;
+                }
+                let assignment = KafkaConsumer::assignment(&self.consumer, config, added, stored)?;
+                if assignment.count() > 0 {
+                    log::info!("Assigning {assignment:?}");
+                    self.consumer.incremental_assign(&assignment)?;
+                }
+            }
+        }
+        *self.topics.write().unwrap_or_else(|e| e.into_inner()) = config.topics.clone();
+        Ok(())
     }
 
-    pub fn start(&self) -> MessageStream<'_, DefaultConsumerContext> {
+    pub fn start(&self) -> MessageStream<'_, KafkaRocksDBContext> {
         self.consumer.stream()
     }
 }
 
-impl<'a> From<&'a KafkaConsumer> for &'a StreamConsumer {
+impl<'a> From<&'a KafkaConsumer> for &'a KafkaStreamConsumer {
     fn from(kc: &'a KafkaConsumer) -> Self {
         &kc.consumer
     }
@@ -14,12 +14,27 @@
  * limitations under the License.
  */
 
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use crate::metadata::{METADATA_CF, column_family_key};
+use crate::offsets::{OFFSETS_CF, Offsets};
+use crate::restore::{self, is_empty_directory};
 use crate::settings::Settings;
 use anyhow::{Result, anyhow};
-use rocksdb::DB;
+use rocksdb::backup::BackupEngine;
+use rocksdb::checkpoint::Checkpoint;
+use rocksdb::{
+    BoundColumnFamily, DBIteratorWithThreadMode, DBWithThreadMode, IteratorMode, MultiThreaded,
+    SnapshotWithThreadMode, WriteBatch,
+};
+
+type RocksDB = DBWithThreadMode<MultiThreaded>;
 
 pub struct Database {
-    db: DB,
+    db: RocksDB,
+    column_families: RwLock<HashMap<String, String>>,
 }
 
 impl Database {
@@ -27,27 +42,344 @@ impl Database {
         let mut options = rocksdb::Options::default();
         options.create_if_missing(true);
         options.create_missing_column_families(true);
-        let cfs: Vec<rocksdb::ColumnFamilyDescriptor> = config
-            .topics
+        let directory = Path::new(&config.rocksdb.directory);
+        if let Some(ref settings) = config.rocksdb.restore
+            && is_empty_directory(directory)?
+        {
+            restore::restore(settings, directory)?;
+        }
+        let mut names: BTreeSet<String> = RocksDB::list_cf(&options, directory)
+            .unwrap_or_default()
+            .into_iter()
+            .collect();
+        names.extend(config.topics.iter().cloned());
+        names.insert(OFFSETS_CF.to_string());
+        names.insert(METADATA_CF.to_string());
+        let cfs: Vec<rocksdb::ColumnFamilyDescriptor> = names
             .iter()
             .map(|t| {
                 let options = rocksdb::Options::default();
                 rocksdb::ColumnFamilyDescriptor::new(t, options)
             })
             .collect();
-        let db = DB::open_cf_descriptors(&options, &config.rocksdb.directory, cfs)?;
-        Ok(Database { db })
+        let db = RocksDB::open_cf_descriptors(&options, directory, cfs)?;
+        let database = Database {
+            db,
+            column_families: RwLock::new(HashMap::new()),
+        };
+        for topic in config.topics.iter() {
+            database.add_topic(topic)?;
+        }
+        Ok(database)
+    }
+
+    fn cf_handle(&self, name: &str) -> Result<Arc<BoundColumnFamily<'_>>> {
+        self.db
+            .cf_handle(name)
+            .ok_or_else(|| anyhow!("RocksDB column family not found"))
+    }
+
+    fn column_families_mut(&self) -> std::sync::RwLockWriteGuard<'_, HashMap<String, String>> {
+        self.column_families
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn stored_column_family(&self, topic: &str) -> Result<String> {
+        let metadata = self.cf_handle(METADATA_CF)?;
+        match self.db.get_cf(&metadata, column_family_key(topic))? {
+            Some(cf) => Ok(String::from_utf8(cf)?),
+            None => Ok(topic.to_string()),
+        }
+    }
+
+    /// Starts storing `topic`, creating its column family if it doesn't exist yet.
+    pub fn add_topic(&self, topic: &str) -> Result<()> {
+        let cf = self.stored_column_family(topic)?;
+        if self.db.cf_handle(&cf).is_none() {
+            self.db.create_cf(&cf, &rocksdb::Options::default())?;
+        }
+        self.column_families_mut().insert(topic.to_string(), cf);
+        Ok(())
+    }
+
+    /// Stops storing `topic`. With `drop`, its column family, mapping and offsets are deleted.
+    pub fn remove_topic(&self, topic: &str, drop: bool) -> Result<()> {
+        let mut column_families = self.column_families_mut();
+        let cf = match column_families.remove(topic) {
+            Some(cf) if drop => cf,
+            _ => return Ok(()),
+        };
+        let mut batch = WriteBatch::default();
+        batch.delete_cf(&self.cf_handle(METADATA_CF)?, column_family_key(topic));
+        let offsets_cf = self.cf_handle(OFFSETS_CF)?;
+        for (_, partition, _) in self.offsets()?.topic(topic).iter() {
+            batch.delete_cf(&offsets_cf, Offsets::key(topic, partition));
+        }
+        self.db.write(batch)?;
+        self.db.drop_cf(&cf)?;
+        Ok(())
+    }
+
+    pub fn has_topic(&self, topic: &str) -> bool {
+        self.column_families
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .contains_key(topic)
+    }
+
+    /// Whether the column family of `topic` holds any records.
+    pub fn has_records(&self, topic: &str) -> Result<bool> {
+        let cf = self.cf_handle(&self.column_family(topic)?)?;
+        let mut records = self.db.iterator_cf(&cf, IteratorMode::Start);
+        Ok(records.next().transpose()?.is_some())
+    }
+
+    /// Returns the name of the column family currently holding the data of `topic`.
+    pub fn column_family(&self, topic: &str) -> Result<String> {
+        self.column_families
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(topic)
+            .cloned()
+            .ok_or_else(|| anyhow!("Unknown topic {topic}"))
+    }
+
+    /// Applies a message and records its offset atomically.
+    pub fn update(
+        &self,
+        topic: &str,
+        partition: i32,
+        offset: i64,
+        key: &[u8],
+        value: Option<&[u8]>,
+    ) -> Result<()> {
+        let column_families = self
+            .column_families
+            .read()
+            .unwrap_or_else(|e| e.into_inner());
+        let cf = column_families
+            .get(topic)
+            .ok_or_else(|| anyhow!("Unknown topic {topic}"))?;
+        let cf = self.cf_handle(cf)?;
+        let mut batch = WriteBatch::default();
+        match value {
+            Some(value) => batch.put_cf(&cf, key, value),
+            None => batch.delete_cf(&cf, key),
+        }
+        self.write(batch, topic, partition, offset)
+    }
+
+    /// Records the offset of a message that doesn't change any data.
+    pub fn store_offset(&self, topic: &str, partition: i32, offset: i64) -> Result<()> {
+        let _column_families = self
+            .column_families
+            .read()
+            .unwrap_or_else(|e| e.into_inner());
+        self.write(WriteBatch::default(), topic, partition, offset)
+    }
+
+    fn write(&self, mut batch: WriteBatch, topic: &str, partition: i32, offset: i64) -> Result<()> {
+        batch.put_cf(
+            &self.cf_handle(OFFSETS_CF)?,
+            Offsets::key(topic, partition),
+            Offsets::value(offset + 1),
+        );
+        self.db.write(batch)?;
+        Ok(())
+    }
+
+    pub fn offsets(&self) -> Result<Offsets> {
+        Offsets::from_rows(
+            self.db
+                .iterator_cf(&self.cf_handle(OFFSETS_CF)?, IteratorMode::Start),
+        )
+    }
+
+    /// Creates an empty column family to rebuild the data of `topic` in.
+    pub fn create_shadow_column_family(&self, topic: &str) -> Result<String> {
+        let name = format!("{topic}@{}", chrono::Utc::now().timestamp_millis());
+        self.db.create_cf(&name, &rocksdb::Options::default())?;
+        Ok(name)
+    }
+
+    pub fn drop_column_family(&self, name: &str) -> Result<()> {
+        self.db.drop_cf(name)?;
+        Ok(())
     }
 
-    pub fn update(&self, topic: &str, key: &[u8], value: Option<&[u8]>) -> Result<()> {
-        let cf = self
-            .db
-            .cf_handle(topic)
-            .ok_or_else(|| anyhow!("RocksDB column family not found"))?;
+    /// Writes to a column family directly, without recording an offset.
+    pub fn put_column_family(&self, name: &str, key: &[u8], value: Option<&[u8]>) -> Result<()> {
+        let cf = self.cf_handle(name)?;
         match value {
-            Some(value) => self.db.put_cf(cf, key, value)?,
-            None => self.db.delete_cf(cf, key)?,
+            Some(value) => self.db.put_cf(&cf, key, value)?,
+            None => self.db.delete_cf(&cf, key)?,
+        }
+        Ok(())
+    }
+
+    /// Replaces the column family of `topic` by `column_family` and drops the old one,
+    /// if `ready` returns `true` for the current offsets. No updates are applied meanwhile.
+    pub fn swap_column_family<F>(&self, topic: &str, column_family: &str, ready: F) -> Result<bool>
+    where
+        F: FnOnce(&Offsets) -> bool,
+    {
+        let mut column_families = self.column_families_mut();
+        if !ready(&self.offsets()?) {
+            return Ok(false);
+        }
+        self.db.put_cf(
+            &self.cf_handle(METADATA_CF)?,
+            column_family_key(topic),
+            column_family.as_bytes(),
+        )?;
+        let old = column_families.insert(topic.to_string(), column_family.to_string());
+        if let Some(old) = old {
+            // Snapshots hold the handles of their column families, and RocksDB only deletes a
+            // dropped column family once its last handle is released, so they keep reading it.
+            self.db.drop_cf(&old)?;
+        }
+        Ok(true)
+    }
+
+    /// Creates a consistent view of all topics and their offsets.
+    ///
+    /// The column families of the topics are resolved together with the RocksDB snapshot, so a
+    /// rebuild or import swapping them in meanwhile doesn't affect reads from the snapshot.
+    pub fn snapshot(&self) -> Snapshot<'_> {
+        let column_families = self
+            .column_families
+            .read()
+            .unwrap_or_else(|e| e.into_inner());
+        Snapshot {
+            db: self,
+            snapshot: self.db.snapshot(),
+            column_families: column_families
+                .iter()
+                .filter_map(|(topic, cf)| Some((topic.clone(), self.db.cf_handle(cf)?)))
+                .collect(),
+        }
+    }
+
+    /// Calls `f` for every record of `topic` in key order and returns the offsets of `topic`,
+    /// both read from the same RocksDB snapshot.
+    pub fn export<F>(&self, topic: &str, mut f: F) -> Result<Offsets>
+    where
+        F: FnMut(&[u8], &[u8]) -> Result<()>,
+    {
+        let snapshot = self.snapshot();
+        for row in snapshot.iterator(topic, IteratorMode::Start)? {
+            let (key, value) = row?;
+            f(&key, &value)?;
+        }
+        Ok(snapshot.offsets()?.topic(topic))
+    }
+
+    /// Replaces the data of `topic` by the given SST files and sets its offsets to `offsets`.
+    pub fn import(&self, topic: &str, files: Vec<PathBuf>, offsets: &Offsets) -> Result<()> {
+        let shadow = self.create_shadow_column_family(topic)?;
+        if !files.is_empty()
+            && let Err(e) = self
+                .db
+                .ingest_external_file_cf(&self.cf_handle(&shadow)?, files)
+        {
+            self.drop_column_family(&shadow)?;
+            return Err(e.into());
+        }
+        let mut column_families = self.column_families_mut();
+        let mut batch = WriteBatch::default();
+        batch.put_cf(
+            &self.cf_handle(METADATA_CF)?,
+            column_family_key(topic),
+            shadow.as_bytes(),
+        );
+        let offsets_cf = self.cf_handle(OFFSETS_CF)?;
+        for (_, partition, offset) in offsets.iter().filter(|(t, _, _)| *t == topic) {
+            batch.put_cf(
+                &offsets_cf,
+                Offsets::key(topic, partition),
+                Offsets::value(offset),
+            );
+        }
+        self.db.write(batch)?;
+        if let Some(old) = column_families.insert(topic.to_string(), shadow) {
+            // Deleted once snapshots reading it are released, see swap_column_family.
+            self.db.drop_cf(&old)?;
+        }
+        Ok(())
+    }
+
+    /// Persists all data to disk and compacts the column families of all topics.
+    pub fn flush_and_compact(&self) -> Result<()> {
+        self.db.flush_wal(true)?;
+        let mut names: Vec<String> = self
+            .column_families
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .values()
+            .cloned()
+            .collect();
+        names.push(OFFSETS_CF.to_string());
+        names.push(METADATA_CF.to_string());
+        for name in names {
+            let cf = self.cf_handle(&name)?;
+            self.db.flush_cf(&cf)?;
+            self.db.compact_range_cf(&cf, None::<&[u8]>, None::<&[u8]>);
         }
         Ok(())
     }
+
+    pub fn create_checkpoint(&self, path: &Path) -> Result<()> {
+        Checkpoint::new(&self.db)?.create_checkpoint(path)?;
+        Ok(())
+    }
+
+    pub fn create_backup(&self, engine: &mut BackupEngine) -> Result<()> {
+        engine.create_new_backup_flush(&self.db, true)?;
+        Ok(())
+    }
+}
+
+/// A consistent view of the database. Every message is applied together with its offset in a
+/// single batch, so the data always corresponds to the offsets read from the same snapshot.
+pub struct Snapshot<'a> {
+    db: &'a Database,
+    snapshot: SnapshotWithThreadMode<'a, RocksDB>,
+    column_families: HashMap<String, Arc<BoundColumnFamily<'a>>>,
+}
+
+impl<'a> Snapshot<'a> {
+    fn cf_handle(&self, topic: &str) -> Result<&Arc<BoundColumnFamily<'a>>> {
+        self.column_families
+            .get(topic)
+            .ok_or_else(|| anyhow!("Unknown topic {topic}"))
+    }
+
+    pub fn offsets(&self) -> Result<Offsets> {
+        Offsets::from_rows(
+            self.snapshot
+                .iterator_cf(&self.db.cf_handle(OFFSETS_CF)?, IteratorMode::Start),
+        )
+    }
+
+    pub fn multi_get<K, I>(&self, topic: &str, keys: I) -> Result<Vec<Option<Vec<u8>>>>
+    where
+        K: AsRef<[u8]>,
+        I: IntoIterator<Item = K>,
+    {
+        let cf = self.cf_handle(topic)?;
+        self.snapshot
+            .multi_get_cf(keys.into_iter().map(|key| (cf, key)))
+            .into_iter()
+            .map(|value| Ok(value?))
+            .collect()
+    }
+
+    pub fn iterator(
+        &self,
+        topic: &str,
+        mode: IteratorMode,
+    ) -> Result<DBIteratorWithThreadMode<'_, RocksDB>> {
+        Ok(self.snapshot.iterator_cf(self.cf_handle(topic)?, mode))
+    }
 }
@@ -0,0 +1,109 @@
+/*
+ * Copyright 2021 Michael Krolikowski
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Digests of the records in key ranges, to find divergent ranges between two stores.
+//!
+//! The digest of a key prefix is the SHA-256 of its records in key order. Along with it, the
+//! digests of its child ranges, i.e. the prefix extended by one byte, are returned. Two stores
+//! can thereby be compared by descending into the children whose digests differ.
+//!
+//! Nothing is cached and parent digests aren't derived from their children: every call reads all
+//! records under the prefix, so a descent of `n` levels reads the records of the divergent range
+//! `n` times.
+
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use serde::Serialize;
+use sha2::{Digest as _, Sha256};
+
+use crate::encoding::hex;
+use crate::offsets::Offsets;
+use crate::store::Store;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RangeDigest {
+    /// Key prefix, hex encoded.
+    pub prefix: String,
+    pub records: u64,
+    /// SHA-256 of the records, hex encoded.
+    pub digest: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Digest {
+    /// Offsets the records correspond to.
+    pub offsets: Offsets,
+    #[serde(flatten)]
+    pub range: RangeDigest,
+    /// Non-empty child ranges. A record whose key equals the prefix isn't part of any child.
+    pub children: Vec<RangeDigest>,
+}
+
+struct Hasher {
+    sha256: Sha256,
+    records: u64,
+}
+
+impl Hasher {
+    fn new() -> Hasher {
+        Hasher {
+            sha256: Sha256::new(),
+            records: 0,
+        }
+    }
+
+    fn update(&mut self, key: &[u8], value: &[u8]) {
+        self.sha256.update((key.len() as u64).to_be_bytes());
+        self.sha256.update(key);
+        self.sha256.update((value.len() as u64).to_be_bytes());
+        self.sha256.update(value);
+        self.records += 1;
+    }
+
+    fn finish(self, prefix: &[u8]) -> RangeDigest {
+        RangeDigest {
+            prefix: hex(prefix),
+            records: self.records,
+            digest: format!("{:x}", self.sha256.finalize()),
+        }
+    }
+}
+
+/// Computes the digest of the records of `topic` whose key starts with `prefix`.
+pub fn digest(store: &impl Store, topic: &str, prefix: &[u8]) -> Result<Digest> {
+    let offsets = store.offsets()?.topic(topic);
+    let mut range = Hasher::new();
+    let mut children: BTreeMap<u8, Hasher> = BTreeMap::new();
+    store.for_each(topic, prefix, &mut |key, value| {
+        range.update(key, value);
+        if let Some(next) = key.get(prefix.len()) {
+            children
+                .entry(*next)
+                .or_insert_with(Hasher::new)
+                .update(key, value);
+        }
+        Ok(())
+    })?;
+    Ok(Digest {
+        offsets,
+        range: range.finish(prefix),
+        children: children
+            .into_iter()
+            .map(|(next, hasher)| hasher.finish(&[prefix, &[next]].concat()))
+            .collect(),
+    })
+}
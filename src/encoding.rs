@@ -0,0 +1,122 @@
+/*
+ * Copyright 2021 Michael Krolikowski
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Conversion of keys and values between their raw bytes and a printable representation.
+
+use anyhow::{Result, anyhow};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use hex_slice::AsHex;
+use serde::Deserialize;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    #[default]
+    Text,
+    Hex,
+    Base64,
+    Json,
+    /// Confluent Schema Registry framed Avro, only supported for output.
+    Avro,
+}
+
+impl Encoding {
+    /// Parses `data`, e.g. a key given on the command line.
+    pub fn decode(&self, data: &str) -> Result<Vec<u8>> {
+        match self {
+            Encoding::Text => Ok(data.as_bytes().to_vec()),
+            Encoding::Hex => (0..data.len())
+                .step_by(2)
+                .map(|i| {
+                    data.get(i..i + 2)
+                        .and_then(|b| u8::from_str_radix(b, 16).ok())
+                        .ok_or_else(|| anyhow!("Invalid hex: {data}"))
+                })
+                .collect(),
+            Encoding::Base64 => Ok(BASE64.decode(data)?),
+            Encoding::Json => Ok(serde_json::to_vec(&serde_json::from_str::<
+                serde_json::Value,
+            >(data)?)?),
+            Encoding::Avro => Err(anyhow!("Avro is only supported for output")),
+        }
+    }
+}
+
+/// Lowercase hex representation of `data`.
+pub fn hex(data: &[u8]) -> String {
+    format!("{:02x}", data.plain_hex(false))
+}
+
+/// Formats raw bytes using an [`Encoding`].
+pub struct Output {
+    encoding: Encoding,
+    #[cfg(feature = "schema_registry")]
+    avro: Option<schema_registry_converter::blocking::avro::AvroDecoder>,
+}
+
+impl Output {
+    /// Creates an output for `encoding`. Avro requires the URL of a Schema Registry.
+    pub fn new(encoding: Encoding, schema_registry: Option<&str>) -> Result<Output> {
+        #[cfg(feature = "schema_registry")]
+        let avro = match (encoding, schema_registry) {
+            (Encoding::Avro, Some(url)) => {
+                Some(schema_registry_converter::blocking::avro::AvroDecoder::new(
+                    schema_registry_converter::blocking::schema_registry::SrSettings::new(
+                        url.to_string(),
+                    ),
+                ))
+            }
+            (Encoding::Avro, None) => return Err(anyhow!("Avro requires a Schema Registry URL")),
+            _ => None,
+        };
+        #[cfg(not(feature = "schema_registry"))]
+        if encoding == Encoding::Avro {
+            let _ = schema_registry;
+            return Err(anyhow!("Avro requires the schema_registry feature"));
+        }
+        Ok(Output {
+            encoding,
+            #[cfg(feature = "schema_registry")]
+            avro,
+        })
+    }
+
+    pub fn encode(&self, data: &[u8]) -> Result<String> {
+        match self.encoding {
+            Encoding::Text => Ok(String::from_utf8_lossy(data).to_string()),
+            Encoding::Hex => Ok(hex(data)),
+            Encoding::Base64 => Ok(BASE64.encode(data)),
+            Encoding::Json => Ok(serde_json::from_slice::<serde_json::Value>(data)?.to_string()),
+            Encoding::Avro => self.avro(data),
+        }
+    }
+
+    #[cfg(feature = "schema_registry")]
+    fn avro(&self, data: &[u8]) -> Result<String> {
+        let decoder = self
+            .avro
+            .as_ref()
+            .ok_or_else(|| anyhow!("Avro requires a Schema Registry URL"))?;
+        let result = decoder.decode(Some(data)).map_err(|e| anyhow!("{e}"))?;
+        Ok(serde_json::Value::try_from(result.value)?.to_string())
+    }
+
+    #[cfg(not(feature = "schema_registry"))]
+    fn avro(&self, _data: &[u8]) -> Result<String> {
+        Err(anyhow!("Avro requires the schema_registry feature"))
+    }
+}
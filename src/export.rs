@@ -0,0 +1,161 @@
+/*
+ * Copyright 2021 Michael Krolikowski
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Export of a topic as sorted SST files, which can be bulk loaded into any RocksDB instance
+//! using `IngestExternalFile`.
+//!
+//! An export consists of the SST files and a `manifest.json` describing the topic, the offsets
+//! the data corresponds to and the key range of every file.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Result, anyhow};
+use rocksdb::SstFileWriter;
+use serde::{Deserialize, Serialize};
+
+use crate::database::Database;
+use crate::encoding::hex;
+use crate::offsets::Offsets;
+use crate::restore::is_empty_directory;
+
+pub const MANIFEST: &str = "manifest.json";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExportFile {
+    pub name: String,
+    pub entries: u64,
+    pub size: u64,
+    /// Smallest key, hex encoded.
+    pub first_key: String,
+    /// Largest key, hex encoded.
+    pub last_key: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExportManifest {
+    pub topic: String,
+    pub timestamp: i64,
+    pub offsets: Offsets,
+    pub entries: u64,
+    pub first_key: Option<String>,
+    pub last_key: Option<String>,
+    pub files: Vec<ExportFile>,
+}
+
+struct SstFile<'a> {
+    writer: SstFileWriter<'a>,
+    file: ExportFile,
+}
+
+impl SstFile<'_> {
+    fn finish(mut self, directory: &Path) -> Result<ExportFile> {
+        self.writer.finish()?;
+        self.file.size = std::fs::metadata(directory.join(&self.file.name))?.len();
+        Ok(self.file)
+    }
+}
+
+/// Writes the data of `topic` into `directory`, starting a new file once `max_file_size` bytes
+/// are exceeded.
+pub fn export(
+    db: &Database,
+    topic: &str,
+    directory: &Path,
+    max_file_size: Option<u64>,
+) -> Result<ExportManifest> {
+    if !is_empty_directory(directory)? {
+        return Err(anyhow!("{} is not empty", directory.display()));
+    }
+    std::fs::create_dir_all(directory)?;
+    let options = rocksdb::Options::default();
+    let mut files = vec![];
+    let mut current: Option<SstFile> = None;
+    let offsets = db.export(topic, |key, value| {
+        let sst = match current {
+            Some(ref mut sst) => sst,
+            None => {
+                let name = format!("{topic}-{:06}.sst", files.len());
+                let writer = SstFileWriter::create(&options);
+                writer.open(directory.join(&name))?;
+                current.insert(SstFile {
+                    writer,
+                    file: ExportFile {
+                        name,
+                        entries: 0,
+                        size: 0,
+                        first_key: hex(key),
+                        last_key: String::new(),
+                    },
+                })
+            }
+        };
+        sst.writer.put(key, value)?;
+        sst.file.entries += 1;
+        sst.file.last_key = hex(key);
+        if max_file_size.is_some_and(|max| sst.writer.file_size() >= max)
+            && let Some(sst) = current.take()
+        {
+            files.push(sst.finish(directory)?);
+        }
+        Ok(())
+    })?;
+    if let Some(sst) = current {
+        files.push(sst.finish(directory)?);
+    }
+    let manifest = ExportManifest {
+        topic: topic.to_string(),
+        timestamp: chrono::Utc::now().timestamp(),
+        offsets,
+        entries: files.iter().map(|f| f.entries).sum(),
+        first_key: files.first().map(|f| f.first_key.clone()),
+        last_key: files.last().map(|f| f.last_key.clone()),
+        files,
+    };
+    std::fs::write(
+        directory.join(MANIFEST),
+        serde_json::to_vec_pretty(&manifest)?,
+    )?;
+    Ok(manifest)
+}
+
+/// Replaces the data of the exported topic, or of `topic` if given, by the export in `directory`.
+///
+/// The offsets of the export only apply to the exported topic, so importing into another topic
+/// keeps that topic's offsets.
+pub fn import(db: &Database, directory: &Path, topic: Option<&str>) -> Result<ExportManifest> {
+    let mut manifest: ExportManifest =
+        serde_json::from_slice(&std::fs::read(directory.join(MANIFEST))?)?;
+    let files: Vec<PathBuf> = manifest
+        .files
+        .iter()
+        .map(|f| directory.join(&f.name))
+        .collect();
+    if let Some(topic) = topic
+        && topic != manifest.topic
+    {
+        log::warn!(
+            "Importing {} into {topic} without its offsets",
+            manifest.topic
+        );
+        manifest.topic = topic.to_string();
+        manifest.offsets = Offsets::default();
+    }
+    if !db.has_topic(&manifest.topic) {
+        return Err(anyhow!("Topic {} is not configured", manifest.topic));
+    }
+    db.import(&manifest.topic, files, &manifest.offsets)?;
+    Ok(manifest)
+}
@@ -0,0 +1,284 @@
+/*
+ * Copyright 2021 Michael Krolikowski
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Commands to inspect the database. They open it as secondary instance, so kafka-rocksdb can
+//! keep running meanwhile.
+
+use std::path::PathBuf;
+
+use anyhow::{Result, anyhow};
+use clap::{Args, Subcommand};
+use kafka_rocksdb::digest::digest;
+use kafka_rocksdb::encoding::{Encoding, Output};
+use kafka_rocksdb::reader::Reader;
+use kafka_rocksdb::settings::Settings;
+use kafka_rocksdb::verify::verify;
+
+use crate::ConfigArgs;
+
+const PROPERTIES: &[&str] = &[
+    "rocksdb.estimate-num-keys",
+    "rocksdb.estimate-live-data-size",
+    "rocksdb.total-sst-files-size",
+    "rocksdb.live-sst-files-size",
+    "rocksdb.cur-size-all-mem-tables",
+    "rocksdb.num-immutable-mem-table",
+    "rocksdb.estimate-pending-compaction-bytes",
+];
+
+#[derive(Args, Debug)]
+pub struct EncodingArgs {
+    #[clap(long, value_enum, default_value_t, help = "Encoding of keys")]
+    key_encoding: Encoding,
+    #[clap(long, value_enum, default_value_t, help = "Encoding of values")]
+    value_encoding: Encoding,
+    #[clap(long, value_name = "url", help = "Schema Registry to decode Avro with")]
+    schema_registry: Option<String>,
+}
+
+impl EncodingArgs {
+    fn outputs(&self) -> Result<(Output, Output)> {
+        let schema_registry = self.schema_registry.as_deref();
+        Ok((
+            Output::new(self.key_encoding, schema_registry)?,
+            Output::new(self.value_encoding, schema_registry)?,
+        ))
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum InspectCommand {
+    #[clap(about = "Print the value of a key")]
+    Get {
+        #[clap(flatten)]
+        config: ConfigArgs,
+        #[clap(value_name = "column family", help = "Topic or column family")]
+        column_family: String,
+        #[clap(value_name = "key", help = "Key in --key-encoding")]
+        key: String,
+        #[clap(flatten)]
+        encoding: EncodingArgs,
+    },
+    #[clap(about = "Print records in key order")]
+    Scan {
+        #[clap(flatten)]
+        config: ConfigArgs,
+        #[clap(value_name = "column family", help = "Topic or column family")]
+        column_family: String,
+        #[clap(
+            long,
+            help = "Only print keys starting with this prefix in --key-encoding"
+        )]
+        prefix: Option<String>,
+        #[clap(long, help = "Maximum number of records to print")]
+        limit: Option<usize>,
+        #[clap(flatten)]
+        encoding: EncodingArgs,
+    },
+    #[clap(about = "Count records")]
+    Count {
+        #[clap(flatten)]
+        config: ConfigArgs,
+        #[clap(value_name = "column family", help = "Topic or column family")]
+        column_family: String,
+        #[clap(
+            long,
+            help = "Only count keys starting with this prefix in --key-encoding"
+        )]
+        prefix: Option<String>,
+        #[clap(long, value_enum, default_value_t, help = "Encoding of the prefix")]
+        key_encoding: Encoding,
+    },
+    #[clap(about = "Print RocksDB properties of column families")]
+    Stats {
+        #[clap(flatten)]
+        config: ConfigArgs,
+        #[clap(
+            value_name = "column family",
+            help = "Topic or column family (default: all)"
+        )]
+        column_family: Option<String>,
+    },
+    #[clap(about = "List column families")]
+    ListCfs {
+        #[clap(flatten)]
+        config: ConfigArgs,
+    },
+    #[clap(about = "Print the next offsets to consume")]
+    Offsets {
+        #[clap(flatten)]
+        config: ConfigArgs,
+    },
+    #[clap(about = "Print the digest of a key range and of its child ranges")]
+    Digest {
+        #[clap(flatten)]
+        config: ConfigArgs,
+        #[clap(value_name = "column family", help = "Topic or column family")]
+        column_family: String,
+        #[clap(long, help = "Hex encoded key prefix of the range")]
+        prefix: Option<String>,
+    },
+    #[clap(about = "Compare the records of a topic to the last values of their keys in Kafka")]
+    Verify {
+        #[clap(flatten)]
+        config: ConfigArgs,
+        #[clap(value_name = "topic", help = "Topic to verify")]
+        topic: String,
+    },
+}
+
+impl InspectCommand {
+    fn config(&self) -> &ConfigArgs {
+        match self {
+            InspectCommand::Get { config, .. }
+            | InspectCommand::Scan { config, .. }
+            | InspectCommand::Count { config, .. }
+            | InspectCommand::Stats { config, .. }
+            | InspectCommand::ListCfs { config }
+            | InspectCommand::Offsets { config }
+            | InspectCommand::Digest { config, .. }
+            | InspectCommand::Verify { config, .. } => config,
+        }
+    }
+
+    pub fn run(&self) -> Result<()> {
+        let settings = self.config().read()?;
+        let secondary = secondary_directory();
+        let result = Reader::open(&settings.rocksdb.directory, &secondary, None)
+            .and_then(|reader| self.inspect(&settings, &reader));
+        if secondary.exists() {
+            std::fs::remove_dir_all(&secondary)?;
+        }
+        result
+    }
+
+    fn inspect(&self, settings: &Settings, reader: &Reader) -> Result<()> {
+        match self {
+            InspectCommand::Get {
+                column_family,
+                key,
+                encoding,
+                ..
+            } => {
+                let (_, value_output) = encoding.outputs()?;
+                let key = encoding.key_encoding.decode(key)?;
+                match reader.get::<Vec<u8>>(column_family, key)? {
+                    Some(value) => println!("{}", value_output.encode(&value)?),
+                    None => return Err(anyhow!("Key not found")),
+                }
+            }
+            InspectCommand::Scan {
+                column_family,
+                prefix,
+                limit,
+                encoding,
+                ..
+            } => {
+                let (key_output, value_output) = encoding.outputs()?;
+                let prefix = match prefix {
+                    Some(prefix) => encoding.key_encoding.decode(prefix)?,
+                    None => vec![],
+                };
+                if *limit == Some(0) {
+                    return Ok(());
+                }
+                let mut count = 0;
+                reader.for_each(column_family, prefix, |key, value| {
+                    println!(
+                        "{}\t{}",
+                        key_output.encode(key)?,
+                        value_output.encode(value)?
+                    );
+                    count += 1;
+                    Ok(limit.is_none_or(|limit| count < limit))
+                })?;
+            }
+            InspectCommand::Count {
+                column_family,
+                prefix,
+                key_encoding,
+                ..
+            } => {
+                let prefix = match prefix {
+                    Some(prefix) => key_encoding.decode(prefix)?,
+                    None => vec![],
+                };
+                let mut count: u64 = 0;
+                reader.for_each(column_family, prefix, |_, _| {
+                    count += 1;
+                    Ok(true)
+                })?;
+                println!("{count}");
+            }
+            InspectCommand::Stats { column_family, .. } => {
+                let column_families = match column_family {
+                    Some(column_family) => vec![column_family.clone()],
+                    None => reader.column_families()?,
+                };
+                for column_family in column_families {
+                    for property in PROPERTIES {
+                        if let Some(value) = reader.property(&column_family, property)? {
+                            println!("{column_family}\t{property}\t{value}");
+                        }
+                    }
+                }
+            }
+            InspectCommand::ListCfs { .. } => {
+                let mapping = reader.column_family_mapping()?;
+                for column_family in reader.column_families()? {
+                    match mapping.iter().find(|(_, cf)| **cf == column_family) {
+                        Some((topic, _)) => println!("{column_family}\t{topic}"),
+                        None => println!("{column_family}"),
+                    }
+                }
+            }
+            InspectCommand::Offsets { .. } => {
+                for (topic, partition, offset) in reader.offsets()?.iter() {
+                    println!("{topic}\t{partition}\t{offset}");
+                }
+            }
+            InspectCommand::Digest {
+                column_family,
+                prefix,
+                ..
+            } => {
+                let prefix = match prefix {
+                    Some(prefix) => Encoding::Hex.decode(prefix)?,
+                    None => vec![],
+                };
+                let digest = digest(reader, column_family, &prefix)?;
+                println!("{}", serde_json::to_string_pretty(&digest)?);
+            }
+            InspectCommand::Verify { topic, .. } => {
+                let report = verify(settings, topic, reader)?;
+                println!("{}", serde_json::to_string_pretty(&report)?);
+                if !report.is_consistent() {
+                    return Err(anyhow!(
+                        "{} missing, {} extra and {} mismatching keys",
+                        report.missing.len(),
+                        report.extra.len(),
+                        report.mismatching.len()
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn secondary_directory() -> PathBuf {
+    std::env::temp_dir().join(format!("kafka-rocksdb-{}", std::process::id()))
+}
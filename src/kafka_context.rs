@@ -0,0 +1,245 @@
+/*
+ * Copyright 2021 Michael Krolikowski
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use rdkafka::config::RDKafkaLogLevel;
+use rdkafka::consumer::{BaseConsumer, Consumer, ConsumerContext, RebalanceProtocol};
+use rdkafka::error::{KafkaError, RDKafkaErrorCode};
+use rdkafka::types::RDKafkaRespErr;
+use rdkafka::{ClientContext, Offset, TopicPartitionList};
+use tokio::sync::watch;
+
+use crate::consumer::start_positions;
+use crate::database::Database;
+use crate::settings::{ConsumerSettings, LibrdkafkaSettings};
+
+struct RateLimiter {
+    interval: Duration,
+    state: Mutex<(Option<Instant>, u64)>,
+}
+
+impl RateLimiter {
+    fn new(interval: Duration) -> RateLimiter {
+        RateLimiter {
+            interval,
+            state: Mutex::new((None, 0)),
+        }
+    }
+
+    /// Returns the number of suppressed events if the current one should be logged.
+    fn check(&self) -> Option<u64> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let (ref mut last, ref mut suppressed) = *state;
+        let now = Instant::now();
+        match last {
+            Some(l) if now.duration_since(*l) < self.interval => {
+                *suppressed += 1;
+                None
+            }
+            _ => {
+                *last = Some(now);
+                Some(std::mem::take(suppressed))
+            }
+        }
+    }
+}
+
+/// Database and settings to position partitions assigned by the group coordinator.
+struct Resume {
+    db: Arc<Database>,
+    consumer: ConsumerSettings,
+}
+
+impl Resume {
+    /// Start offsets of the assigned `partitions`, see [`KafkaRocksDBContext::resuming_from`].
+    fn positions(
+        &self,
+        consumer: &BaseConsumer<KafkaRocksDBContext>,
+        partitions: &TopicPartitionList,
+    ) -> Result<TopicPartitionList> {
+        let stored = self.db.offsets()?;
+        let mut configured = TopicPartitionList::new();
+        let mut committed = vec![];
+        for elem in partitions.elements() {
+            let (topic, partition) = (elem.topic(), elem.partition());
+            if stored.get(topic, partition).is_none()
+                && self.db.has_topic(topic)
+                && self.db.has_records(topic)?
+            {
+                committed.push((topic.to_string(), partition));
+            } else {
+                configured.add_partition(topic, partition);
+            }
+        }
+        let mut positions = start_positions(consumer, &self.consumer, &stored, &configured)?;
+        for (topic, partition) in committed {
+            positions.add_partition_offset(&topic, partition, Offset::Stored)?;
+        }
+        Ok(positions)
+    }
+}
+
+pub struct KafkaRocksDBContext {
+    broker_down: RateLimiter,
+    resume: Option<Resume>,
+    rebalance_failure: watch::Sender<Option<String>>,
+}
+
+impl KafkaRocksDBContext {
+    pub fn new(config: &LibrdkafkaSettings) -> KafkaRocksDBContext {
+        KafkaRocksDBContext {
+            broker_down: RateLimiter::new(Duration::from_secs(config.broker_down_log_interval)),
+            resume: None,
+            rebalance_failure: watch::channel(None).0,
+        }
+    }
+
+    /// Starts partitions assigned on a rebalance at the offsets stored in `db`, or at the
+    /// configured start positions, instead of the offsets committed to the group. The stored
+    /// offsets are written together with the data, so they are correct even if the database
+    /// was restored from a backup or a topic was dropped and added again. Partitions without
+    /// stored offsets of topics which already hold records were written by versions not storing
+    /// offsets, so they resume from the offsets committed to the group.
+    pub fn resuming_from(mut self, db: Arc<Database>, consumer: &ConsumerSettings) -> Self {
+        self.resume = Some(Resume {
+            db,
+            consumer: consumer.clone(),
+        });
+        self
+    }
+
+    /// Resolves once partitions assigned on a rebalance couldn't be positioned. They are left
+    /// unassigned, so nothing consumes them until the pipeline is stopped.
+    pub async fn rebalance_failure(&self) -> String {
+        let mut failures = self.rebalance_failure.subscribe();
+        let failure = failures
+            .wait_for(Option::is_some)
+            .await
+            .map(|failure| (*failure).clone());
+        match failure {
+            Ok(Some(failure)) => failure,
+            // The sender is owned by `self`, so it isn't dropped while waiting.
+            _ => std::future::pending().await,
+        }
+    }
+
+    fn is_broker_down_log(fac: &str, log_message: &str) -> bool {
+        fac == "FAIL" || log_message.contains("brokers are down")
+    }
+
+    fn is_broker_down_error(error: &KafkaError) -> bool {
+        matches!(
+            error.rdkafka_error_code(),
+            Some(RDKafkaErrorCode::BrokerTransportFailure | RDKafkaErrorCode::AllBrokersDown)
+        )
+    }
+}
+
+fn log_level(level: RDKafkaLogLevel) -> log::Level {
+    match level {
+        RDKafkaLogLevel::Emerg
+        | RDKafkaLogLevel::Alert
+        | RDKafkaLogLevel::Critical
+        | RDKafkaLogLevel::Error => log::Level::Error,
+        RDKafkaLogLevel::Warning => log::Level::Warn,
+        RDKafkaLogLevel::Notice | RDKafkaLogLevel::Info => log::Level::Info,
+        RDKafkaLogLevel::Debug => log::Level::Debug,
+    }
+}
+
+impl ClientContext for KafkaRocksDBContext {
+    fn log(&self, level: RDKafkaLogLevel, fac: &str, log_message: &str) {
+        let target = format!("librdkafka::{}", fac.to_lowercase());
+        let level = log_level(level);
+        if !log::log_enabled!(target: &target, level) {
+            return;
+        }
+        if level > log::Level::Warn || !Self::is_broker_down_log(fac, log_message) {
+            log::log!(target: &target, level, "{log_message}");
+        } else if let Some(suppressed) = self.broker_down.check() {
+            log::log!(
+                target: &target,
+                level,
+                "{log_message} ({suppressed} similar messages suppressed)"
+            );
+        }
+    }
+
+    fn error(&self, error: KafkaError, reason: &str) {
+        if !Self::is_broker_down_error(&error) {
+            log::error!(target: "librdkafka", "{error}: {reason}");
+        } else if let Some(suppressed) = self.broker_down.check() {
+            log::error!(
+                target: "librdkafka",
+                "{error}: {reason} ({suppressed} similar messages suppressed)"
+            );
+        }
+    }
+}
+
+impl ConsumerContext for KafkaRocksDBContext {
+    fn rebalance(
+        &self,
+        base_consumer: &BaseConsumer<Self>,
+        err: RDKafkaRespErr,
+        tpl: &mut TopicPartitionList,
+    ) {
+        let cooperative = matches!(
+            base_consumer.rebalance_protocol(),
+            RebalanceProtocol::Cooperative
+        );
+        let result = match err {
+            RDKafkaRespErr::RD_KAFKA_RESP_ERR__ASSIGN_PARTITIONS => {
+                if let Some(ref resume) = self.resume {
+                    match resume.positions(base_consumer, tpl) {
+                        Ok(positioned) => *tpl = positioned,
+                        Err(e) => {
+                            // Consuming from the committed offsets could skip data.
+                            let failure = format!("Failed to position {tpl:?}: {e:#}");
+                            log::error!("{failure}");
+                            self.rebalance_failure.send_replace(Some(failure));
+                            return;
+                        }
+                    }
+                }
+                log::info!("Assigning {tpl:?}");
+                if cooperative {
+                    base_consumer.incremental_assign(tpl)
+                } else {
+                    base_consumer.assign(tpl)
+                }
+            }
+            RDKafkaRespErr::RD_KAFKA_RESP_ERR__REVOKE_PARTITIONS => {
+                log::info!("Revoking {tpl:?}");
+                if cooperative {
+                    base_consumer.incremental_unassign(tpl)
+                } else {
+                    base_consumer.unassign()
+                }
+            }
+            err => {
+                log::error!("Error rebalancing: {}", RDKafkaErrorCode::from(err));
+                base_consumer.unassign()
+            }
+        };
+        if let Err(e) = result {
+            log::error!("Failed to apply rebalance: {e}");
+        }
+    }
+}
@@ -14,54 +14,164 @@
  * limitations under the License.
  */
 
-use anyhow::Result;
+use std::sync::{Arc, OnceLock};
+
+use anyhow::{Result, anyhow};
 use futures::StreamExt;
+use prometheus::IntCounter;
 use rdkafka::Message;
+use rdkafka::consumer::Consumer;
+use rdkafka::message::BorrowedMessage;
 
-use crate::consumer::KafkaConsumer;
+use crate::consumer::{KafkaConsumer, KafkaStreamConsumer};
 use crate::database::Database;
 use crate::kafka_stream_ext::KafkaStreamExt;
-use crate::settings::Settings;
+use crate::settings::{ErrorPolicy, Settings};
 use crate::stream_signal_ext::StreamSignalExt;
+use crate::telemetry::{in_message_span, in_span};
 
 pub struct KafkaRocksDB {
+    pipeline: String,
     consumer: KafkaConsumer,
-    db: Database,
+    db: Arc<Database>,
+    until_end: bool,
+    on_error: ErrorPolicy,
+    messages: IntCounter,
 }
 
 impl KafkaRocksDB {
     pub fn new(config: &Settings) -> Result<KafkaRocksDB> {
-        let consumer = KafkaConsumer::new(config)?;
-        let db = Database::new(config)?;
-        Ok(KafkaRocksDB { consumer, db })
+        let db = Arc::new(Database::new(config)?);
+        let consumer = KafkaConsumer::new(config, &db)?;
+        Ok(KafkaRocksDB {
+            pipeline: config.pipeline.clone(),
+            consumer,
+            db,
+            until_end: config.consumer.until_end,
+            on_error: config.consumer.on_error,
+            messages: crate::metrics::MESSAGES.with_label_values(&[&config.pipeline]),
+        })
     }
 
-    pub async fn start(&self) -> Result<()> {
+    /// Name of the pipeline, see [`Settings::pipelines`].
+    pub fn pipeline(&self) -> &str {
+        &self.pipeline
+    }
+
+    pub fn database(&self) -> Arc<Database> {
+        self.db.clone()
+    }
+
+    /// Starts consuming the `added` topics and stops consuming the `removed` ones, dropping
+    /// their column families if `drop` is set. `config` contains the new list of topics.
+    pub fn update_topics(
+        &self,
+        config: &Settings,
+        added: &[String],
+        removed: &[String],
+        drop: bool,
+    ) -> Result<()> {
+        for topic in added {
+            self.db.add_topic(topic)?;
+        }
         self.consumer
+            .update_topics(config, &self.db.offsets()?, added, removed)?;
+        for topic in removed {
+            self.db.remove_topic(topic, drop)?;
+        }
+        Ok(())
+    }
+
+    /// Consumes the configured topics. In until-end mode this returns once all partitions
+    /// reached the high watermarks seen on startup, after flushing and compacting RocksDB.
+    /// With `on_error = "fail"` this returns the first error writing to RocksDB. Partitions
+    /// assigned by the group coordinator which can't be positioned stop the pipeline as well.
+    pub async fn start(&self) -> Result<()> {
+        let end = if self.until_end {
+            let end = self.consumer.end_offsets(&self.db.offsets()?)?;
+            log::info!("Consuming {} until {end:?}", self.pipeline);
+            Some(end)
+        } else {
+            None
+        };
+        let failure = OnceLock::new();
+        let consumer: &KafkaStreamConsumer = (&self.consumer).into();
+        let consume = self
+            .consumer
             .start()
+            .until_end(&self.consumer, end)
             .map(|msg| {
                 let msg = msg?;
-                crate::metrics::MESSAGES.inc();
-                match msg.key() {
-                    Some(key) => match self.db.update(msg.topic(), key, msg.payload()) {
-                        Ok(_) => Ok(msg),
-                        Err(e) => {
-                            log::error!("Failed to update RocksDB: {e}");
-                            Err(e)
-                        }
-                    },
-                    _ => {
-                        log::error!("Ignoring message without key: {msg:?}");
-                        Ok(msg)
-                    }
-                }
+                self.messages.inc();
+                in_message_span("kafka-rocksdb.message", &msg, || {
+                    self.process(&msg, &failure)?;
+                    self.store_offset(&msg);
+                    Ok::<_, anyhow::Error>(())
+                })?;
+                Ok::<_, anyhow::Error>(msg)
             })
-            .try_store_offsets(&self.consumer)
+            .take_while(|_| futures::future::ready(failure.get().is_none()))
             .until_termination()
-            .for_each(|_| async {})
-            .await;
+            .for_each(|_| async {});
+        tokio::select! {
+            () = consume => {}
+            e = consumer.context().rebalance_failure() => {
+                let _ = failure.set(e);
+            }
+        }
+        if let Some(e) = failure.into_inner() {
+            return Err(anyhow!(
+                "Stopped pipeline {} after an error: {e}",
+                self.pipeline
+            ));
+        }
+        if self.until_end {
+            log::info!("Reached the end of all partitions, flushing and compacting RocksDB");
+            let db = self.db.clone();
+            tokio::task::spawn_blocking(move || db.flush_and_compact()).await??;
+        }
         Ok(())
     }
+
+    fn process(&self, msg: &BorrowedMessage, failure: &OnceLock<String>) -> Result<()> {
+        if !self.db.has_topic(msg.topic()) {
+            // Fetched before the topic was removed by a reload.
+            return Ok(());
+        }
+        match msg.key() {
+            Some(key) => in_span("kafka-rocksdb.process", || {
+                self.db.update(
+                    msg.topic(),
+                    msg.partition(),
+                    msg.offset(),
+                    key,
+                    msg.payload(),
+                )
+            })
+            .inspect_err(|e| {
+                log::error!("Failed to update RocksDB of {}: {e}", self.pipeline);
+                if self.on_error == ErrorPolicy::Fail {
+                    let _ = failure.set(e.to_string());
+                }
+            }),
+            _ => {
+                log::error!("Ignoring message without key: {msg:?}");
+                self.db
+                    .store_offset(msg.topic(), msg.partition(), msg.offset())
+            }
+        }
+    }
+
+    /// Stores the offset of a processed message, to be committed to the consumer group.
+    fn store_offset(&self, msg: &BorrowedMessage) {
+        let consumer: &KafkaStreamConsumer = (&self.consumer).into();
+        let stored = in_span("kafka-rocksdb.store_offset", || {
+            consumer.store_offset(msg.topic(), msg.partition(), msg.offset())
+        });
+        if let Err(e) = stored {
+            log::warn!("Failed to store offset: {}", e);
+        }
+    }
 }
 
 impl Drop for KafkaRocksDB {
@@ -14,73 +14,95 @@
  * limitations under the License.
  */
 
+use std::collections::HashMap;
 use std::pin::Pin;
 
-use futures::Stream;
 use futures::task::{Context, Poll};
+use futures::{Stream, ready};
 use pin_project::pin_project;
-use rdkafka::Message;
 use rdkafka::consumer::Consumer;
-use rdkafka::consumer::StreamConsumer;
-use rdkafka::error::KafkaError;
+use rdkafka::error::{KafkaError, KafkaResult};
 use rdkafka::message::BorrowedMessage;
+use rdkafka::{Message, Offset};
 
-#[pin_project]
-pub struct StoreOffsets<'a, T>
-where
-    T: Stream,
-{
-    #[pin]
-    stream: T,
-    consumer: &'a StreamConsumer,
-}
+use crate::consumer::KafkaStreamConsumer;
 
-#[pin_project]
-pub struct TryStoreOffsets<'a, T>
-where
-    T: Stream,
-{
-    #[pin]
-    stream: T,
-    consumer: &'a StreamConsumer,
+/// Offsets to consume up to, e.g. the high watermarks at startup.
+#[derive(Debug, Default)]
+pub struct EndOffsets {
+    targets: HashMap<(String, i32), i64>,
+    positions: HashMap<(String, i32), i64>,
 }
 
-macro_rules! poll_store_offsets {
-    ( $( $return_error:expr )* ) => {
-        fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-            let this = self.project();
-            let polled = this.stream.poll_next(cx);
-            if let Poll::Ready(Some(Ok(ref msg))) = polled {
-                if let Err(e) = this.consumer.store_offset(msg.topic(), msg.partition(), msg.offset()) {
-                    log::warn!("Failed to store offset: {}", e);
-                    $(
-                        $return_error
-                        return Poll::Ready(Some(Err(e.into())))
-                    )*
-                }
-            }
-            polled
+impl EndOffsets {
+    pub fn insert(&mut self, topic: &str, partition: i32, position: i64, target: i64) {
+        let key = (topic.to_string(), partition);
+        self.targets.insert(key.clone(), target);
+        self.positions.insert(key, position);
+    }
+
+    fn update(&mut self, topic: &str, partition: i32, position: i64) {
+        if let Some(p) = self.positions.get_mut(&(topic.to_string(), partition)) {
+            *p = (*p).max(position);
         }
-    };
+    }
+
+    pub fn reached(&self) -> bool {
+        self.targets
+            .iter()
+            .all(|(key, target)| self.positions.get(key).is_some_and(|p| p >= target))
+    }
 }
 
-impl<'a, T, E> Stream for StoreOffsets<'a, T>
+#[pin_project]
+pub struct UntilEnd<'a, T>
 where
-    T: Stream<Item = Result<BorrowedMessage<'a>, E>>,
-    E: From<KafkaError>,
+    T: Stream,
 {
-    type Item = T::Item;
-
-    poll_store_offsets!({});
+    #[pin]
+    stream: T,
+    consumer: &'a KafkaStreamConsumer,
+    end: Option<EndOffsets>,
 }
 
-impl<'a, T, E> Stream for TryStoreOffsets<'a, T>
+impl<'a, T> Stream for UntilEnd<'a, T>
 where
-    T: Stream<Item = Result<BorrowedMessage<'a>, E>>,
+    T: Stream<Item = KafkaResult<BorrowedMessage<'a>>>,
 {
     type Item = T::Item;
 
-    poll_store_offsets!();
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut this = self.project();
+        let end = match this.end {
+            Some(end) => end,
+            None => return this.stream.poll_next(cx),
+        };
+        loop {
+            if end.reached() {
+                return Poll::Ready(None);
+            }
+            match ready!(this.stream.as_mut().poll_next(cx)) {
+                Some(Ok(msg)) => {
+                    end.update(msg.topic(), msg.partition(), msg.offset() + 1);
+                    return Poll::Ready(Some(Ok(msg)));
+                }
+                Some(Err(KafkaError::PartitionEOF(partition))) => {
+                    // The end of the partition might be beyond the last message, e.g. due to
+                    // transaction markers, so rely on the consumer's position.
+                    if let Ok(positions) = this.consumer.position() {
+                        for elem in positions.elements() {
+                            if elem.partition() == partition
+                                && let Offset::Offset(offset) = elem.offset()
+                            {
+                                end.update(elem.topic(), partition, offset);
+                            }
+                        }
+                    }
+                }
+                other => return Poll::Ready(other),
+            }
+        }
+    }
 }
 
 impl<'a, S: ?Sized, E> KafkaStreamExt<'a> for S where
@@ -89,14 +111,16 @@ impl<'a, S: ?Sized, E> KafkaStreamExt<'a> for S where
 }
 
 pub trait KafkaStreamExt<'a>: Stream {
-    fn try_store_offsets<C>(self, consumer: C) -> TryStoreOffsets<'a, Self>
+    /// Ends the stream once all partitions reached `end`. Without `end` the stream is unbounded.
+    fn until_end<C>(self, consumer: C, end: Option<EndOffsets>) -> UntilEnd<'a, Self>
     where
         Self: Sized,
-        C: Into<&'a StreamConsumer>,
+        C: Into<&'a KafkaStreamConsumer>,
     {
-        TryStoreOffsets {
+        UntilEnd {
             consumer: consumer.into(),
             stream: self,
+            end,
         }
     }
 }
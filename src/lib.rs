@@ -14,4 +14,33 @@
  * limitations under the License.
  */
 
+pub mod admin;
+pub mod backup;
+pub mod consumer;
+pub mod database;
+pub mod digest;
+pub mod encoding;
+pub mod export;
+pub mod kafka_context;
+pub mod kafka_rocksdb;
+pub mod kafka_stream_ext;
 pub mod logging;
+pub mod metadata;
+pub mod metrics;
+pub mod offsets;
+pub mod prometheus_exporter;
+pub mod query;
+pub mod reader;
+pub mod rebuild;
+pub mod reload;
+pub mod restore;
+pub mod secrets;
+pub mod settings;
+pub mod signals;
+#[cfg(feature = "s3")]
+pub mod snapshot;
+pub mod store;
+pub mod stream_signal_ext;
+pub mod telemetry;
+pub mod validate;
+pub mod verify;
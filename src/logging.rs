@@ -14,9 +14,63 @@
  * limitations under the License.
  */
 
+use std::sync::RwLock;
+
 use anyhow::Result;
+use log::{LevelFilter, Metadata};
 
-pub fn setup_logger() -> Result<()> {
+use crate::settings::LoggingSettings;
+
+struct Levels {
+    level: LevelFilter,
+    targets: Vec<(String, LevelFilter)>,
+}
+
+static LEVELS: RwLock<Levels> = RwLock::new(Levels {
+    level: LevelFilter::Info,
+    targets: Vec::new(),
+});
+
+impl Levels {
+    /// The level of the most specific target matching `target`.
+    fn level(&self, target: &str) -> LevelFilter {
+        self.targets
+            .iter()
+            .filter(|(t, _)| {
+                target
+                    .strip_prefix(t.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .max_by_key(|(t, _)| t.len())
+            .map_or(self.level, |(_, level)| *level)
+    }
+}
+
+fn enabled(metadata: &Metadata) -> bool {
+    let levels = LEVELS.read().unwrap_or_else(|e| e.into_inner());
+    metadata.level() <= levels.level(metadata.target())
+}
+
+/// Applies log levels, also while logging is already set up.
+pub fn configure(settings: &LoggingSettings) {
+    let levels = Levels {
+        level: settings.level.into(),
+        targets: settings
+            .targets
+            .iter()
+            .map(|(target, level)| (target.clone(), (*level).into()))
+            .collect(),
+    };
+    let max = levels
+        .targets
+        .iter()
+        .map(|(_, level)| *level)
+        .fold(levels.level, LevelFilter::max);
+    *LEVELS.write().unwrap_or_else(|e| e.into_inner()) = levels;
+    log::set_max_level(max);
+}
+
+fn dispatch() -> fern::Dispatch {
     fern::Dispatch::new()
         .format(|out, message, record| {
             out.finish(format_args!(
@@ -27,8 +81,38 @@ pub fn setup_logger() -> Result<()> {
                 message
             ))
         })
-        .level(log::LevelFilter::Info)
-        .chain(std::io::stdout())
-        .apply()?;
+        .filter(enabled)
+}
+
+pub fn setup_logger() -> Result<()> {
+    dispatch().chain(std::io::stdout()).apply()?;
+    configure(&LoggingSettings::default());
+    Ok(())
+}
+
+/// Logs to stderr, for tools writing their output to stdout.
+pub fn setup_stderr_logger() -> Result<()> {
+    dispatch().chain(std::io::stderr()).apply()?;
+    configure(&LoggingSettings::default());
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uses_most_specific_target() {
+        let levels = Levels {
+            level: LevelFilter::Info,
+            targets: vec![
+                ("librdkafka".to_string(), LevelFilter::Debug),
+                ("librdkafka::fetch".to_string(), LevelFilter::Off),
+            ],
+        };
+        assert_eq!(levels.level("librdkafka::cgrp"), LevelFilter::Debug);
+        assert_eq!(levels.level("librdkafka::fetch"), LevelFilter::Off);
+        assert_eq!(levels.level("librdkafka_other"), LevelFilter::Info);
+        assert_eq!(levels.level("kafka_rocksdb"), LevelFilter::Info);
+    }
+}
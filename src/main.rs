@@ -14,51 +14,326 @@
  * limitations under the License.
  */
 
-use anyhow::Result;
-use clap::Parser;
-use futures::future::FutureExt;
-
-use crate::logging::setup_logger;
-use crate::prometheus_exporter::PrometheusExporter;
-use crate::settings::Settings;
-
-mod consumer;
-mod database;
-mod kafka_rocksdb;
-mod kafka_stream_ext;
-mod logging;
-mod metrics;
-mod prometheus_exporter;
-mod settings;
-mod signals;
-mod stream_signal_ext;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{Result, anyhow};
+use axum::Router;
+use clap::{Args, Parser, Subcommand};
+use futures::future::{FutureExt, LocalBoxFuture};
+
+use kafka_rocksdb::backup::Backups;
+use kafka_rocksdb::database::Database;
+use kafka_rocksdb::kafka_rocksdb::KafkaRocksDB;
+use kafka_rocksdb::logging::{self, setup_logger};
+use kafka_rocksdb::prometheus_exporter::PrometheusExporter;
+use kafka_rocksdb::rebuild::Rebuilds;
+use kafka_rocksdb::reload::Reloader;
+use kafka_rocksdb::settings::{Settings, SettingsSources};
+#[cfg(feature = "s3")]
+use kafka_rocksdb::snapshot;
+use kafka_rocksdb::telemetry::Telemetry;
+use kafka_rocksdb::{admin, export, metrics, query, validate};
+
+mod inspect;
+
+#[derive(Args, Debug)]
+struct OverrideArgs {
+    #[clap(
+        long = "config",
+        value_name = "file",
+        help = "Configuration file overlaying the previous ones, may be repeated"
+    )]
+    overlays: Vec<String>,
+    #[clap(
+        long = "set",
+        value_name = "key=value",
+        help = "Override a setting, e.g. kafka.group.id=test, may be repeated"
+    )]
+    overrides: Vec<String>,
+}
+
+impl OverrideArgs {
+    fn sources(&self, config_file: &str) -> SettingsSources {
+        let mut files = vec![config_file.to_string()];
+        files.extend(self.overlays.iter().cloned());
+        SettingsSources {
+            files,
+            overrides: self.overrides.clone(),
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+struct ConfigArgs {
+    #[clap(
+        value_name = "configuration file",
+        help = "Configuration file to use, TOML, YAML or JSON by extension"
+    )]
+    config_file: String,
+    #[clap(flatten)]
+    overrides: OverrideArgs,
+    #[clap(
+        long,
+        value_name = "name",
+        help = "Pipeline to use if several are configured"
+    )]
+    pipeline: Option<String>,
+}
+
+impl ConfigArgs {
+    fn sources(&self) -> SettingsSources {
+        self.overrides.sources(&self.config_file)
+    }
+
+    /// Reads the settings of the selected pipeline and checks its RocksDB settings.
+    fn read(&self) -> Result<Settings> {
+        let settings =
+            Settings::read_sources(&self.sources())?.pipeline(self.pipeline.as_deref())?;
+        validate::check_rocksdb(&settings)?;
+        Ok(settings)
+    }
+}
+
+#[derive(Args, Debug)]
+struct RunArgs {
+    #[clap(flatten)]
+    config: ConfigArgs,
+    #[clap(
+        long,
+        help = "Consume until the high watermarks seen on startup, then compact and exit"
+    )]
+    until_end: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    #[clap(about = "Consume the configured topics into RocksDB (default)")]
+    Run(RunArgs),
+    #[clap(
+        about = "Re-materialize the column family of a topic from the beginning, while kafka-rocksdb is stopped"
+    )]
+    Rebuild {
+        #[clap(flatten)]
+        config: ConfigArgs,
+        #[clap(value_name = "topic", help = "Topic to rebuild")]
+        topic: String,
+    },
+    #[clap(about = "Export the data of a topic as SST files")]
+    Export {
+        #[clap(flatten)]
+        config: ConfigArgs,
+        #[clap(value_name = "topic", help = "Topic to export")]
+        topic: String,
+        #[clap(value_name = "directory", help = "Empty directory to export to")]
+        directory: String,
+        #[clap(
+            long,
+            value_name = "bytes",
+            help = "Split into files of about this size"
+        )]
+        max_file_size: Option<u64>,
+    },
+    #[clap(about = "Replace the data of a topic by an export")]
+    Import {
+        #[clap(flatten)]
+        config: ConfigArgs,
+        #[clap(value_name = "directory", help = "Directory containing the export")]
+        directory: String,
+        #[clap(long, help = "Topic to import into instead of the exported one")]
+        topic: Option<String>,
+    },
+    #[clap(about = "Check a configuration file and report all problems")]
+    Validate {
+        #[clap(flatten)]
+        config: ConfigArgs,
+    },
+    #[clap(about = "Print the JSON Schema of the configuration")]
+    Schema,
+    #[clap(flatten)]
+    Inspect(inspect::InspectCommand),
+}
 
 #[derive(Parser, Debug)]
-#[clap(author, about, version)]
+#[clap(author, about, version, subcommand_negates_reqs = true)]
 struct CommandLineOptions {
     #[clap(
         value_name = "configuration file",
-        help = "Configuration file to use",
+        help = "Configuration file to use, TOML, YAML or JSON by extension",
         required = true
     )]
-    config_file: String,
+    config_file: Option<String>,
+    #[clap(flatten)]
+    overrides: OverrideArgs,
+    #[clap(
+        long,
+        help = "Consume until the high watermarks seen on startup, then compact and exit"
+    )]
+    until_end: bool,
+    #[clap(subcommand)]
+    command: Option<Command>,
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let opts = CommandLineOptions::parse();
-    setup_logger()?;
-    let settings = Settings::read(&opts.config_file)?;
+#[cfg(feature = "s3")]
+fn snapshot_schedule(
+    db: Arc<Database>,
+    settings: &Settings,
+) -> Result<Option<LocalBoxFuture<'static, Result<()>>>> {
+    let Some(ref snapshots) = settings.snapshots else {
+        return Ok(None);
+    };
+    let uploader = snapshot::SnapshotUploader::new(
+        db,
+        snapshots,
+        &settings.rocksdb.directory,
+        &settings.pipeline,
+    )?;
+    Ok(Some(Arc::new(uploader).schedule().boxed_local()))
+}
 
+#[cfg(not(feature = "s3"))]
+fn snapshot_schedule(
+    _db: Arc<Database>,
+    settings: &Settings,
+) -> Result<Option<LocalBoxFuture<'static, Result<()>>>> {
+    if settings.snapshots.is_some() {
+        log::warn!("Ignoring [snapshots] settings: built without the s3 feature");
+    }
+    Ok(None)
+}
+
+async fn run(sources: SettingsSources, until_end: bool) -> Result<()> {
+    let settings = Settings::read_sources(&sources)?;
+    logging::configure(&settings.logging);
+    validate::check(&settings)?;
+    let _telemetry = Telemetry::init(&settings)?;
     metrics::initialize_metrics();
-    let prometheus = PrometheusExporter::start(&settings).fuse();
 
-    let kafka_rocksdb = kafka_rocksdb::KafkaRocksDB::new(&settings)?;
-    let kafka_rocksdb = kafka_rocksdb.start().fuse();
+    let mut pipelines = vec![];
+    let mut routes = Router::new();
+    let mut schedules = vec![];
+    for mut pipeline in settings.pipelines() {
+        pipeline.consumer.until_end |= until_end;
+        let kafka_rocksdb = KafkaRocksDB::new(&pipeline)?;
+        let db = kafka_rocksdb.database();
+        let backups = pipeline
+            .backup
+            .as_ref()
+            .map(|backup| Arc::new(Backups::new(db.clone(), backup, &pipeline.pipeline)));
+        let rebuilds = Arc::new(Rebuilds::new(db.clone(), &pipeline));
+        let pipeline_routes =
+            admin::router(backups.clone(), rebuilds).merge(query::router(db.clone()));
+        routes = if settings.pipelines.is_empty() {
+            routes.merge(pipeline_routes)
+        } else {
+            routes.nest(
+                &format!("/pipelines/{}", pipeline.pipeline),
+                pipeline_routes,
+            )
+        };
+        if let Some(backups) = backups {
+            schedules.push(backups.schedule().boxed_local());
+        }
+        schedules.extend(snapshot_schedule(db, &pipeline)?);
+        pipelines.push(kafka_rocksdb);
+    }
+
+    let prometheus = PrometheusExporter::start(&settings, routes).fuse();
+    let schedule = async {
+        futures::future::try_join_all(schedules).await?;
+        futures::future::pending::<Result<()>>().await
+    }
+    .fuse();
+    let reload = Reloader::new(sources, settings.clone(), &pipelines)
+        .watch()
+        .fuse();
+    let consume = futures::future::try_join_all(pipelines.iter().map(|p| p.start())).fuse();
 
     tokio::select!(
         result = prometheus => result?,
-        result = kafka_rocksdb => result?,
+        result = schedule => result?,
+        result = reload => result?,
+        result = consume => {
+            result?;
+        }
+    );
+    Ok(())
+}
+
+async fn rebuild(settings: Settings, topic: &str) -> Result<()> {
+    let db = Arc::new(Database::new(&settings)?);
+    Rebuilds::new(db, &settings).rebuild(topic).await
+}
+
+fn export(
+    settings: Settings,
+    topic: &str,
+    directory: &str,
+    max_file_size: Option<u64>,
+) -> Result<()> {
+    let db = Database::new(&settings)?;
+    let manifest = export::export(&db, topic, Path::new(directory), max_file_size)?;
+    log::info!(
+        "Exported {} records of {topic} into {} files",
+        manifest.entries,
+        manifest.files.len()
+    );
+    Ok(())
+}
+
+fn import(settings: Settings, directory: &str, topic: Option<&str>) -> Result<()> {
+    let db = Database::new(&settings)?;
+    let manifest = export::import(&db, Path::new(directory), topic)?;
+    log::info!(
+        "Imported {} records into {}",
+        manifest.entries,
+        manifest.topic
     );
     Ok(())
 }
+
+fn validate(config: &ConfigArgs) -> Result<()> {
+    let validation = validate::validate(&config.sources());
+    for warning in validation.warnings.iter() {
+        println!("warning: {warning}");
+    }
+    for error in validation.errors.iter() {
+        println!("error: {error}");
+    }
+    if !validation.is_valid() {
+        return Err(anyhow!("{} is invalid", config.config_file));
+    }
+    println!("{} is valid", config.config_file);
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let opts = CommandLineOptions::parse();
+    setup_logger()?;
+    match opts.command {
+        None => {
+            let config_file = opts.config_file.unwrap_or_default();
+            run(opts.overrides.sources(&config_file), opts.until_end).await
+        }
+        Some(Command::Run(args)) => run(args.config.sources(), args.until_end).await,
+        Some(Command::Rebuild { config, topic }) => rebuild(config.read()?, &topic).await,
+        Some(Command::Export {
+            config,
+            topic,
+            directory,
+            max_file_size,
+        }) => export(config.read()?, &topic, &directory, max_file_size),
+        Some(Command::Import {
+            config,
+            directory,
+            topic,
+        }) => import(config.read()?, &directory, topic.as_deref()),
+        Some(Command::Validate { config }) => validate(&config),
+        Some(Command::Schema) => {
+            println!("{}", validate::json_schema()?);
+            Ok(())
+        }
+        Some(Command::Inspect(command)) => command.run(),
+    }
+}
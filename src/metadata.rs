@@ -0,0 +1,23 @@
+/*
+ * Copyright 2021 Michael Krolikowski
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+/// Column family mapping topics to the column family currently holding their data.
+/// Topics without an entry are stored in a column family of the same name.
+pub const METADATA_CF: &str = "__metadata";
+
+pub fn column_family_key(topic: &str) -> Vec<u8> {
+    format!("cf/{topic}").into_bytes()
+}
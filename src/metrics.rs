@@ -15,13 +15,39 @@
  */
 
 use lazy_static::lazy_static;
-use prometheus::{IntCounter, opts, register_int_counter};
+use prometheus::{
+    IntCounterVec, IntGaugeVec, opts, register_int_counter_vec, register_int_gauge_vec,
+};
 
 lazy_static! {
-    pub static ref MESSAGES: IntCounter =
-        register_int_counter!(opts!("messages", "Number of messages received.")).unwrap();
+    pub static ref MESSAGES: IntCounterVec = register_int_counter_vec!(
+        opts!("messages", "Number of messages received."),
+        &["pipeline"]
+    )
+    .unwrap();
+    pub static ref BACKUPS: IntCounterVec = register_int_counter_vec!(
+        opts!("backups", "Number of backups created."),
+        &["pipeline"]
+    )
+    .unwrap();
+    pub static ref SNAPSHOTS: IntCounterVec = register_int_counter_vec!(
+        opts!("snapshots", "Number of snapshots uploaded."),
+        &["pipeline"]
+    )
+    .unwrap();
+    pub static ref REBUILD_REMAINING: IntGaugeVec = register_int_gauge_vec!(
+        opts!(
+            "rebuild_remaining",
+            "Number of messages left to consume by a rebuild."
+        ),
+        &["pipeline", "topic"]
+    )
+    .unwrap();
 }
 
 pub fn initialize_metrics() {
     MESSAGES.reset();
+    BACKUPS.reset();
+    SNAPSHOTS.reset();
+    REBUILD_REMAINING.reset();
 }
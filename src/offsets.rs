@@ -0,0 +1,108 @@
+/*
+ * Copyright 2021 Michael Krolikowski
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{Result, anyhow};
+use rocksdb::{DB, IteratorMode};
+use serde::{Deserialize, Serialize};
+
+/// Column family holding the next Kafka offset to consume per topic and partition.
+/// It is written in the same batch as the data so both stay consistent.
+pub const OFFSETS_CF: &str = "__offsets";
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Offsets(BTreeMap<String, BTreeMap<i32, i64>>);
+
+impl Offsets {
+    pub fn key(topic: &str, partition: i32) -> Vec<u8> {
+        format!("{topic}/{partition}").into_bytes()
+    }
+
+    pub fn value(offset: i64) -> [u8; 8] {
+        offset.to_be_bytes()
+    }
+
+    pub fn from_rows<K, V, E, I>(rows: I) -> Result<Offsets>
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+        E: std::error::Error + Send + Sync + 'static,
+        I: IntoIterator<Item = Result<(K, V), E>>,
+    {
+        let mut offsets = Offsets::default();
+        for row in rows {
+            let (k, v) = row?;
+            let key = std::str::from_utf8(k.as_ref())?;
+            let (topic, partition) = key
+                .rsplit_once('/')
+                .ok_or_else(|| anyhow!("Invalid offset key: {key}"))?;
+            let offset: [u8; 8] = v
+                .as_ref()
+                .try_into()
+                .map_err(|_| anyhow!("Invalid offset value for {key}"))?;
+            offsets.insert(topic, partition.parse()?, i64::from_be_bytes(offset));
+        }
+        Ok(offsets)
+    }
+
+    /// Reads the offsets of a database that isn't opened by this process, e.g. a checkpoint.
+    pub fn read(path: &Path) -> Result<Offsets> {
+        let options = rocksdb::Options::default();
+        if !DB::list_cf(&options, path)?
+            .iter()
+            .any(|cf| cf == OFFSETS_CF)
+        {
+            return Ok(Offsets::default());
+        }
+        let db = DB::open_cf_for_read_only(&options, path, [OFFSETS_CF], false)?;
+        let cf = db
+            .cf_handle(OFFSETS_CF)
+            .ok_or_else(|| anyhow!("RocksDB column family not found"))?;
+        Offsets::from_rows(db.iterator_cf(cf, IteratorMode::Start))
+    }
+
+    pub fn insert(&mut self, topic: &str, partition: i32, offset: i64) {
+        self.0
+            .entry(topic.to_string())
+            .or_default()
+            .insert(partition, offset);
+    }
+
+    pub fn get(&self, topic: &str, partition: i32) -> Option<i64> {
+        self.0.get(topic).and_then(|p| p.get(&partition)).copied()
+    }
+
+    /// Returns the offsets of `topic` only.
+    pub fn topic(&self, topic: &str) -> Offsets {
+        let mut offsets = Offsets::default();
+        if let Some(partitions) = self.0.get(topic) {
+            offsets.0.insert(topic.to_string(), partitions.clone());
+        }
+        offsets
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, i32, i64)> {
+        self.0
+            .iter()
+            .flat_map(|(t, p)| p.iter().map(move |(p, o)| (t.as_str(), *p, *o)))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
@@ -41,9 +41,11 @@ impl PrometheusExporter {
         result.into_response()
     }
 
-    pub async fn start(config: &Settings) -> Result<()> {
+    pub async fn start(config: &Settings, routes: Router) -> Result<()> {
         let addr: SocketAddr = config.prometheus.address.parse()?;
-        let app = Router::new().route("/metrics", get(PrometheusExporter::metrics));
+        let app = Router::new()
+            .route("/metrics", get(PrometheusExporter::metrics))
+            .merge(routes);
         let listener = TcpListener::bind(addr).await?;
         axum::serve(listener, app).await?;
         Ok(())
@@ -0,0 +1,221 @@
+/*
+ * Copyright 2021 Michael Krolikowski
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! HTTP API to read records. Every request is served from a single RocksDB snapshot and
+//! reports the offsets the returned records correspond to.
+
+use std::sync::Arc;
+
+use anyhow::{Result, anyhow};
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use rocksdb::{Direction, IteratorMode};
+use serde::{Deserialize, Serialize};
+
+use crate::admin::blocking;
+use crate::database::Database;
+use crate::digest::digest;
+use crate::encoding::{Encoding, Output};
+use crate::offsets::Offsets;
+
+const DEFAULT_LIMIT: usize = 100;
+
+#[derive(Debug, Deserialize)]
+struct EncodingParams {
+    #[serde(default)]
+    encoding: Encoding,
+}
+
+#[derive(Debug, Deserialize)]
+struct ScanParams {
+    #[serde(default)]
+    encoding: Encoding,
+    prefix: Option<String>,
+    from: Option<String>,
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DigestParams {
+    /// Hex encoded key prefix.
+    prefix: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct MultiGetRequest {
+    keys: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Record {
+    key: String,
+    value: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Records {
+    offsets: Offsets,
+    records: Vec<Record>,
+    /// Key to continue a scan from, if the limit was reached.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next: Option<String>,
+}
+
+fn bad_request(e: anyhow::Error) -> Response {
+    (StatusCode::BAD_REQUEST, e.to_string()).into_response()
+}
+
+fn multi_get(db: &Database, topic: &str, keys: Vec<Vec<u8>>, output: Output) -> Result<Records> {
+    let snapshot = db.snapshot();
+    let values = snapshot.multi_get(topic, &keys)?;
+    let records = keys
+        .iter()
+        .zip(values)
+        .map(|(key, value)| {
+            Ok(Record {
+                key: output.encode(key)?,
+                value: value.map(|v| output.encode(&v)).transpose()?,
+            })
+        })
+        .collect::<Result<_>>()?;
+    Ok(Records {
+        offsets: snapshot.offsets()?.topic(topic),
+        records,
+        next: None,
+    })
+}
+
+fn scan(
+    db: &Database,
+    topic: &str,
+    prefix: Vec<u8>,
+    from: Option<Vec<u8>>,
+    limit: usize,
+    output: Output,
+) -> Result<Records> {
+    let snapshot = db.snapshot();
+    let start = from
+        .filter(|from| *from > prefix)
+        .unwrap_or_else(|| prefix.clone());
+    let mut records = vec![];
+    let mut next = None;
+    for row in snapshot.iterator(topic, IteratorMode::From(&start, Direction::Forward))? {
+        let (key, value) = row?;
+        if !key.starts_with(&prefix) {
+            break;
+        }
+        if records.len() == limit {
+            next = Some(output.encode(&key)?);
+            break;
+        }
+        records.push(Record {
+            key: output.encode(&key)?,
+            value: Some(output.encode(&value)?),
+        });
+    }
+    Ok(Records {
+        offsets: snapshot.offsets()?.topic(topic),
+        records,
+        next,
+    })
+}
+
+async fn get_record(
+    State(db): State<Arc<Database>>,
+    Path((topic, key)): Path<(String, String)>,
+    Query(params): Query<EncodingParams>,
+) -> Response {
+    let (key, output) = match params
+        .encoding
+        .decode(&key)
+        .and_then(|key| Ok((key, Output::new(params.encoding, None)?)))
+    {
+        Ok(request) => request,
+        Err(e) => return bad_request(e),
+    };
+    blocking(move || multi_get(&db, &topic, vec![key], output)).await
+}
+
+async fn get_records(
+    State(db): State<Arc<Database>>,
+    Path(topic): Path<String>,
+    Query(params): Query<EncodingParams>,
+    Json(request): Json<MultiGetRequest>,
+) -> Response {
+    let (keys, output) = match request
+        .keys
+        .iter()
+        .map(|key| params.encoding.decode(key))
+        .collect::<Result<Vec<_>>>()
+        .and_then(|keys| Ok((keys, Output::new(params.encoding, None)?)))
+    {
+        Ok(request) => request,
+        Err(e) => return bad_request(e),
+    };
+    blocking(move || multi_get(&db, &topic, keys, output)).await
+}
+
+async fn scan_records(
+    State(db): State<Arc<Database>>,
+    Path(topic): Path<String>,
+    Query(params): Query<ScanParams>,
+) -> Response {
+    let encoding = params.encoding;
+    let prefix = match params.prefix.map(|p| encoding.decode(&p)).transpose() {
+        Ok(prefix) => prefix.unwrap_or_default(),
+        Err(e) => return bad_request(e),
+    };
+    let from = match params.from.map(|f| encoding.decode(&f)).transpose() {
+        // Keys with the prefix sort before any other key after the prefix.
+        Ok(Some(from)) if from > prefix && !from.starts_with(&prefix) => {
+            return bad_request(anyhow!("from is beyond all keys with the prefix"));
+        }
+        Ok(from) => from,
+        Err(e) => return bad_request(e),
+    };
+    let output = match Output::new(encoding, None) {
+        Ok(output) => output,
+        Err(e) => return bad_request(e),
+    };
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT);
+    blocking(move || scan(&db, &topic, prefix, from, limit, output)).await
+}
+
+async fn digest_records(
+    State(db): State<Arc<Database>>,
+    Path(topic): Path<String>,
+    Query(params): Query<DigestParams>,
+) -> Response {
+    let prefix = match params.prefix.map(|p| Encoding::Hex.decode(&p)).transpose() {
+        Ok(prefix) => prefix.unwrap_or_default(),
+        Err(e) => return bad_request(e),
+    };
+    blocking(move || digest(&db.snapshot(), &topic, &prefix)).await
+}
+
+pub fn router(db: Arc<Database>) -> Router {
+    Router::new()
+        .route(
+            "/topics/{topic}/records",
+            get(scan_records).post(get_records),
+        )
+        .route("/topics/{topic}/records/{key}", get(get_record))
+        .route("/topics/{topic}/digest", get(digest_records))
+        .with_state(db)
+}
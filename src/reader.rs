@@ -0,0 +1,280 @@
+/*
+ * Copyright 2021 Michael Krolikowski
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Read access to a database maintained by a running kafka-rocksdb, using a RocksDB
+//! [secondary instance](https://github.com/facebook/rocksdb/wiki/Secondary-instance).
+//!
+//! ```no_run
+//! # use std::time::Duration;
+//! # use kafka_rocksdb::reader::Reader;
+//! let reader = Reader::open("./db", "./db.secondary", Some(Duration::from_secs(1)))?;
+//! let value: Option<String> = reader.get("test", b"key")?;
+//! let offsets = reader.offsets()?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use anyhow::{Result, anyhow};
+use rocksdb::{DBWithThreadMode, Direction, IteratorMode, MultiThreaded, Options};
+use serde::de::DeserializeOwned;
+
+use crate::metadata::METADATA_CF;
+use crate::offsets::{OFFSETS_CF, Offsets};
+
+type RocksDB = DBWithThreadMode<MultiThreaded>;
+
+/// Conversion of keys and values read from RocksDB.
+pub trait Decode: Sized {
+    fn decode(data: &[u8]) -> Result<Self>;
+}
+
+impl Decode for Vec<u8> {
+    fn decode(data: &[u8]) -> Result<Self> {
+        Ok(data.to_vec())
+    }
+}
+
+impl Decode for String {
+    fn decode(data: &[u8]) -> Result<Self> {
+        Ok(String::from_utf8(data.to_vec())?)
+    }
+}
+
+/// Decodes JSON encoded data.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Json<T>(pub T);
+
+impl<T: DeserializeOwned> Decode for Json<T> {
+    fn decode(data: &[u8]) -> Result<Self> {
+        Ok(Json(serde_json::from_slice(data)?))
+    }
+}
+
+struct Secondary {
+    primary: PathBuf,
+    secondary: PathBuf,
+    db: RwLock<(Arc<RocksDB>, BTreeSet<String>)>,
+}
+
+impl Secondary {
+    fn column_families(primary: &Path) -> Result<BTreeSet<String>> {
+        Ok(RocksDB::list_cf(&Options::default(), primary)?
+            .into_iter()
+            .collect())
+    }
+
+    fn open(primary: &Path, secondary: &Path) -> Result<(Arc<RocksDB>, BTreeSet<String>)> {
+        let column_families = Self::column_families(primary)?;
+        let db = RocksDB::open_cf_as_secondary(
+            &Options::default(),
+            primary,
+            secondary,
+            &column_families,
+        )?;
+        db.try_catch_up_with_primary()?;
+        Ok((Arc::new(db), column_families))
+    }
+
+    fn db(&self) -> Arc<RocksDB> {
+        self.db.read().unwrap_or_else(|e| e.into_inner()).0.clone()
+    }
+
+    /// Catches up with the primary and reopens the database if column families were
+    /// created or dropped, e.g. by a rebuild.
+    fn catch_up(&self) -> Result<()> {
+        let (db, opened) = self.db.read().unwrap_or_else(|e| e.into_inner()).clone();
+        db.try_catch_up_with_primary()?;
+        if Self::column_families(&self.primary)? != opened {
+            log::info!(
+                "Column families changed, reopening {}",
+                self.primary.display()
+            );
+            let reopened = Self::open(&self.primary, &self.secondary)?;
+            *self.db.write().unwrap_or_else(|e| e.into_inner()) = reopened;
+        }
+        Ok(())
+    }
+}
+
+/// A read-only view of a database, which optionally catches up with the primary periodically.
+pub struct Reader {
+    secondary: Arc<Secondary>,
+    _stop: Option<mpsc::Sender<()>>,
+}
+
+impl Reader {
+    /// Opens the database in `primary` as secondary instance, keeping its own files in
+    /// `secondary`. With `catch_up_interval`, new data becomes visible in that interval.
+    pub fn open<P, S>(
+        primary: P,
+        secondary: S,
+        catch_up_interval: Option<Duration>,
+    ) -> Result<Reader>
+    where
+        P: AsRef<Path>,
+        S: AsRef<Path>,
+    {
+        let primary = primary.as_ref().to_path_buf();
+        let secondary = secondary.as_ref().to_path_buf();
+        let db = Secondary::open(&primary, &secondary)?;
+        let secondary = Arc::new(Secondary {
+            primary,
+            secondary,
+            db: RwLock::new(db),
+        });
+        let stop = catch_up_interval.map(|interval| {
+            let (stop, stopped) = mpsc::channel::<()>();
+            let secondary = secondary.clone();
+            std::thread::spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    if let Err(e) = secondary.catch_up() {
+                        log::warn!("Failed to catch up with primary: {e}");
+                    }
+                }
+            });
+            stop
+        });
+        Ok(Reader {
+            secondary,
+            _stop: stop,
+        })
+    }
+
+    /// Makes the latest data of the primary visible.
+    pub fn catch_up(&self) -> Result<()> {
+        self.secondary.catch_up()
+    }
+
+    /// Names of all column families, including internal ones.
+    pub fn column_families(&self) -> Result<Vec<String>> {
+        Ok(Secondary::column_families(&self.secondary.primary)?
+            .into_iter()
+            .collect())
+    }
+
+    /// Topics mapped to a column family other than their own, e.g. after a rebuild.
+    pub fn column_family_mapping(&self) -> Result<BTreeMap<String, String>> {
+        let db = self.secondary.db();
+        let cf = match db.cf_handle(METADATA_CF) {
+            Some(cf) => cf,
+            None => return Ok(BTreeMap::new()),
+        };
+        let mut mapping = BTreeMap::new();
+        for row in db.prefix_iterator_cf(&cf, b"cf/") {
+            let (key, value) = row?;
+            if let Some(topic) = key.strip_prefix(b"cf/") {
+                mapping.insert(
+                    String::from_utf8(topic.to_vec())?,
+                    String::from_utf8(value.to_vec())?,
+                );
+            }
+        }
+        Ok(mapping)
+    }
+
+    /// Topics stored in the database, without the internal column families and the shadow
+    /// column families of rebuilds.
+    pub fn topics(&self) -> Result<Vec<String>> {
+        let mut topics: BTreeSet<String> = self.column_family_mapping()?.into_keys().collect();
+        topics.extend(
+            self.column_families()?
+                .into_iter()
+                .filter(|cf| !matches!(cf.as_str(), "default" | OFFSETS_CF | METADATA_CF))
+                .filter(|cf| !cf.contains('@')),
+        );
+        Ok(topics.into_iter().collect())
+    }
+
+    /// Name of the column family currently holding the data of `topic`.
+    pub fn column_family(&self, topic: &str) -> Result<String> {
+        Ok(self
+            .column_family_mapping()?
+            .remove(topic)
+            .unwrap_or_else(|| topic.to_string()))
+    }
+
+    /// The next offsets to be consumed by the primary.
+    pub fn offsets(&self) -> Result<Offsets> {
+        let db = self.secondary.db();
+        match db.cf_handle(OFFSETS_CF) {
+            Some(cf) => Offsets::from_rows(db.iterator_cf(&cf, IteratorMode::Start)),
+            None => Ok(Offsets::default()),
+        }
+    }
+
+    pub fn get<V: Decode>(&self, topic: &str, key: impl AsRef<[u8]>) -> Result<Option<V>> {
+        let column_family = self.column_family(topic)?;
+        let db = self.secondary.db();
+        let cf = db
+            .cf_handle(&column_family)
+            .ok_or_else(|| anyhow!("RocksDB column family {column_family} not found"))?;
+        db.get_cf(&cf, key)?.map(|v| V::decode(&v)).transpose()
+    }
+
+    /// Reads a RocksDB property, e.g. `rocksdb.estimate-num-keys`, of the column family of `topic`.
+    pub fn property(&self, topic: &str, name: &str) -> Result<Option<String>> {
+        let column_family = self.column_family(topic)?;
+        let db = self.secondary.db();
+        let cf = db
+            .cf_handle(&column_family)
+            .ok_or_else(|| anyhow!("RocksDB column family {column_family} not found"))?;
+        Ok(db.property_value_cf(&cf, name)?)
+    }
+
+    /// Returns up to `limit` records of `topic` whose key starts with `prefix`, in key order.
+    pub fn scan<K: Decode, V: Decode>(
+        &self,
+        topic: &str,
+        prefix: impl AsRef<[u8]>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(K, V)>> {
+        let mut records = vec![];
+        if limit == Some(0) {
+            return Ok(records);
+        }
+        self.for_each(topic, prefix, |key, value| {
+            records.push((K::decode(key)?, V::decode(value)?));
+            Ok(limit.is_none_or(|limit| records.len() < limit))
+        })?;
+        Ok(records)
+    }
+
+    /// Calls `f` for every record of `topic` whose key starts with `prefix`, in key order,
+    /// until it returns `false`.
+    pub fn for_each<F>(&self, topic: &str, prefix: impl AsRef<[u8]>, mut f: F) -> Result<()>
+    where
+        F: FnMut(&[u8], &[u8]) -> Result<bool>,
+    {
+        let prefix = prefix.as_ref();
+        let column_family = self.column_family(topic)?;
+        let db = self.secondary.db();
+        let cf = db
+            .cf_handle(&column_family)
+            .ok_or_else(|| anyhow!("RocksDB column family {column_family} not found"))?;
+        for row in db.iterator_cf(&cf, IteratorMode::From(prefix, Direction::Forward)) {
+            let (key, value) = row?;
+            if !key.starts_with(prefix) || !f(&key, &value)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
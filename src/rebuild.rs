@@ -0,0 +1,198 @@
+/*
+ * Copyright 2021 Michael Krolikowski
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Re-materializes a topic into a shadow column family while the current one keeps serving reads.
+//!
+//! The shadow column family is swapped in once the rebuild consumer has reached both the high
+//! watermarks recorded when the rebuild started and the offsets stored by the running pipeline.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Result, anyhow};
+use futures::StreamExt;
+use rdkafka::consumer::Consumer;
+use rdkafka::{ClientConfig, Message, Offset, TopicPartitionList};
+
+use crate::consumer::{KafkaStreamConsumer, kafka_client_config};
+use crate::database::Database;
+use crate::kafka_context::KafkaRocksDBContext;
+use crate::offsets::Offsets;
+use crate::settings::{LibrdkafkaSettings, Settings};
+
+const METADATA_TIMEOUT: Duration = Duration::from_secs(10);
+const POSITION_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct Rebuilds {
+    pipeline: String,
+    db: Arc<Database>,
+    client_config: ClientConfig,
+    librdkafka: LibrdkafkaSettings,
+    active: Mutex<HashSet<String>>,
+}
+
+/// Number of messages left until partitions reach their target offsets.
+fn remaining(
+    topic: &str,
+    positions: &HashMap<i32, i64>,
+    targets: &HashMap<i32, i64>,
+    offsets: &Offsets,
+) -> i64 {
+    targets
+        .iter()
+        .map(|(partition, target)| {
+            let target = (*target).max(offsets.get(topic, *partition).unwrap_or(0));
+            let position = positions.get(partition).copied().unwrap_or(0);
+            (target - position).max(0)
+        })
+        .sum()
+}
+
+impl Rebuilds {
+    pub fn new(db: Arc<Database>, config: &Settings) -> Rebuilds {
+        let mut client_config = kafka_client_config(config);
+        let group_id = config
+            .kafka
+            .get("group.id")
+            .map(String::as_str)
+            .unwrap_or("kafka_rocksdb");
+        client_config.set("group.id", format!("{group_id}-rebuild"));
+        client_config.set("enable.auto.commit", "false");
+        // Rebuilds end at their target offsets, not at the end of partitions as until-end runs do.
+        client_config.set("enable.partition.eof", "false");
+        Rebuilds {
+            pipeline: config.pipeline.clone(),
+            db,
+            client_config,
+            librdkafka: config.librdkafka.clone(),
+            active: Mutex::new(HashSet::new()),
+        }
+    }
+
+    fn begin(&self, topic: &str) -> Result<()> {
+        if !self.db.has_topic(topic) {
+            return Err(anyhow!("Unknown topic {topic}"));
+        }
+        let mut active = self.active.lock().unwrap_or_else(|e| e.into_inner());
+        if !active.insert(topic.to_string()) {
+            return Err(anyhow!("Rebuild of {topic} is already running"));
+        }
+        Ok(())
+    }
+
+    fn end(&self, topic: &str) {
+        let mut active = self.active.lock().unwrap_or_else(|e| e.into_inner());
+        active.remove(topic);
+        crate::metrics::REBUILD_REMAINING
+            .with_label_values(&[self.pipeline.as_str(), topic])
+            .set(0);
+    }
+
+    /// Rebuilds the column family of `topic` and waits for it to be swapped in.
+    pub async fn rebuild(&self, topic: &str) -> Result<()> {
+        self.begin(topic)?;
+        let result = self.run(topic).await;
+        self.end(topic);
+        result
+    }
+
+    /// Starts rebuilding the column family of `topic` in the background.
+    pub fn spawn(self: &Arc<Self>, topic: &str) -> Result<()> {
+        self.begin(topic)?;
+        let rebuilds = self.clone();
+        let topic = topic.to_string();
+        tokio::spawn(async move {
+            match rebuilds.run(&topic).await {
+                Ok(()) => log::info!("Rebuilt {topic}"),
+                Err(e) => log::error!("Failed to rebuild {topic}: {e}"),
+            }
+            rebuilds.end(&topic);
+        });
+        Ok(())
+    }
+
+    async fn run(&self, topic: &str) -> Result<()> {
+        let shadow = self.db.create_shadow_column_family(topic)?;
+        log::info!("Rebuilding {topic} into column family {shadow}");
+        let result = self.materialize(topic, &shadow).await;
+        if result.is_err()
+            && let Err(e) = self.db.drop_column_family(&shadow)
+        {
+            log::warn!("Failed to drop column family {shadow}: {e}");
+        }
+        result
+    }
+
+    async fn materialize(&self, topic: &str, shadow: &str) -> Result<()> {
+        let consumer: KafkaStreamConsumer = self
+            .client_config
+            .create_with_context(KafkaRocksDBContext::new(&self.librdkafka))?;
+        let metadata = consumer.fetch_metadata(Some(topic), METADATA_TIMEOUT)?;
+        let mut tpl = TopicPartitionList::new();
+        let mut positions = HashMap::new();
+        let mut targets = HashMap::new();
+        for partition in metadata
+            .topics()
+            .iter()
+            .filter(|t| t.name() == topic)
+            .flat_map(|t| t.partitions())
+        {
+            let (low, high) = consumer.fetch_watermarks(topic, partition.id(), METADATA_TIMEOUT)?;
+            positions.insert(partition.id(), low);
+            targets.insert(partition.id(), high);
+            tpl.add_partition_offset(topic, partition.id(), Offset::Beginning)?;
+        }
+        if targets.is_empty() {
+            return Err(anyhow!("Topic {topic} has no partitions"));
+        }
+        consumer.assign(&tpl)?;
+
+        let remaining_metric =
+            crate::metrics::REBUILD_REMAINING.with_label_values(&[self.pipeline.as_str(), topic]);
+        let mut stream = consumer.stream();
+        loop {
+            let left = remaining(topic, &positions, &targets, &self.db.offsets()?);
+            remaining_metric.set(left);
+            if left == 0
+                && self.db.swap_column_family(topic, shadow, |offsets| {
+                    remaining(topic, &positions, &targets, offsets) == 0
+                })?
+            {
+                return Ok(());
+            }
+            match tokio::time::timeout(POSITION_INTERVAL, stream.next()).await {
+                Ok(Some(msg)) => {
+                    let msg = msg?;
+                    if let Some(key) = msg.key() {
+                        self.db.put_column_family(shadow, key, msg.payload())?;
+                    }
+                    positions.insert(msg.partition(), msg.offset() + 1);
+                }
+                Ok(None) => return Err(anyhow!("Kafka stream ended")),
+                Err(_) => {
+                    // Compacted or aborted messages leave gaps, so rely on the consumer's position.
+                    for elem in consumer.position()?.elements_for_topic(topic) {
+                        if let Offset::Offset(offset) = elem.offset() {
+                            let position = positions.entry(elem.partition()).or_insert(offset);
+                            *position = (*position).max(offset);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,198 @@
+/*
+ * Copyright 2021 Michael Krolikowski
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Reloads the configuration on SIGHUP or when a configuration file changes.
+//!
+//! Topics, logging and reload settings are applied while running. Changes of all other
+//! settings are reported and require a restart.
+
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use futures::StreamExt;
+use tokio::signal::unix::SignalKind;
+
+use crate::kafka_rocksdb::KafkaRocksDB;
+use crate::settings::{Settings, SettingsSources};
+use crate::signals::signals;
+use crate::validate::validate_topic;
+
+pub struct Reloader<'a> {
+    sources: SettingsSources,
+    settings: Settings,
+    pipelines: &'a [KafkaRocksDB],
+}
+
+fn modified(sources: &SettingsSources) -> Vec<Option<SystemTime>> {
+    sources
+        .files
+        .iter()
+        .map(|file| {
+            std::fs::metadata(Path::new(file))
+                .and_then(|m| m.modified())
+                .ok()
+        })
+        .collect()
+}
+
+/// Names of the sections of the pipeline `new` which differ from `current` and can't be
+/// reloaded.
+fn non_reloadable_changes(current: &Settings, new: &Settings) -> Vec<&'static str> {
+    let mut changes = vec![];
+    if current.kafka != new.kafka {
+        changes.push("kafka");
+    }
+    if current.librdkafka != new.librdkafka {
+        changes.push("librdkafka");
+    }
+    if current.consumer != new.consumer {
+        changes.push("consumer");
+    }
+    if current.rocksdb != new.rocksdb {
+        changes.push("rocksdb");
+    }
+    if current.backup != new.backup {
+        changes.push("backup");
+    }
+    if current.snapshots != new.snapshots {
+        changes.push("snapshots");
+    }
+    changes
+}
+
+/// Names of the sections shared by all pipelines which differ and can't be reloaded.
+fn non_reloadable_shared_changes(current: &Settings, new: &Settings) -> Vec<&'static str> {
+    let mut changes = vec![];
+    if current.prometheus != new.prometheus {
+        changes.push("prometheus");
+    }
+    if current.opentelemetry != new.opentelemetry {
+        changes.push("opentelemetry");
+    }
+    if current.pipelines.keys().ne(new.pipelines.keys()) {
+        changes.push("pipelines");
+    }
+    changes
+}
+
+/// Diffs the topics of `current` and `new` into the added and removed ones.
+fn topic_changes(current: &[String], new: &[String]) -> (Vec<String>, Vec<String>) {
+    let added = new
+        .iter()
+        .filter(|t| !current.contains(t))
+        .cloned()
+        .collect();
+    let removed = current
+        .iter()
+        .filter(|t| !new.contains(t))
+        .cloned()
+        .collect();
+    (added, removed)
+}
+
+impl<'a> Reloader<'a> {
+    /// `settings` are the settings the `pipelines` were started with.
+    pub fn new(
+        sources: SettingsSources,
+        settings: Settings,
+        pipelines: &'a [KafkaRocksDB],
+    ) -> Self {
+        Reloader {
+            sources,
+            settings,
+            pipelines,
+        }
+    }
+
+    /// Reloads on SIGHUP and, with `reload.interval`, whenever one of the configuration files
+    /// was modified. Never returns unless the signal handler can't be installed.
+    pub async fn watch(mut self) -> Result<()> {
+        let mut hangup = signals(&[SignalKind::hangup()])?;
+        let files = self.sources.files.join(", ");
+        let mut last_modified = modified(&self.sources);
+        loop {
+            let interval = self.settings.reload.interval.map(Duration::from_secs);
+            let file_check = async {
+                match interval {
+                    Some(interval) => tokio::time::sleep(interval).await,
+                    None => futures::future::pending().await,
+                }
+            };
+            tokio::select! {
+                _ = hangup.next() => log::info!("Received SIGHUP, reloading {files}"),
+                _ = file_check => {
+                    if modified(&self.sources) == last_modified {
+                        continue;
+                    }
+                    log::info!("Configuration was modified, reloading {files}");
+                }
+            }
+            last_modified = modified(&self.sources);
+            if let Err(e) = self.reload() {
+                log::error!("Failed to reload {files}: {e:#}");
+            }
+        }
+    }
+
+    fn reload(&mut self) -> Result<()> {
+        let new = Settings::read_sources(&self.sources)?;
+        let new_pipelines = new.pipelines();
+        for topic in new_pipelines.iter().flat_map(|p| p.topics.iter()) {
+            validate_topic(topic)?;
+        }
+        for section in non_reloadable_shared_changes(&self.settings, &new) {
+            log::warn!("Changes of [{section}] can't be reloaded and require a restart");
+        }
+
+        crate::logging::configure(&new.logging);
+        self.settings.logging = new.logging;
+        self.settings.reload = new.reload;
+
+        for current in self.settings.pipelines() {
+            let name = &current.pipeline;
+            let Some(updated) = new_pipelines.iter().find(|p| &p.pipeline == name) else {
+                continue;
+            };
+            for section in non_reloadable_changes(&current, updated) {
+                log::warn!(
+                    "Changes of [{section}] of pipeline {name} can't be reloaded and require a restart"
+                );
+            }
+            let (added, removed) = topic_changes(&current.topics, &updated.topics);
+            if added.is_empty() && removed.is_empty() {
+                continue;
+            }
+            let Some(kafka_rocksdb) = self.pipelines.iter().find(|k| k.pipeline() == name) else {
+                continue;
+            };
+            log::info!("Adding topics {added:?}, removing topics {removed:?} of pipeline {name}");
+            let mut settings = current.clone();
+            settings.topics = updated.topics.clone();
+            kafka_rocksdb.update_topics(
+                &settings,
+                &added,
+                &removed,
+                self.settings.reload.drop_removed_topics,
+            )?;
+            match self.settings.pipelines.get_mut(name) {
+                Some(pipeline) => pipeline.topics = settings.topics,
+                None => self.settings.topics = settings.topics,
+            }
+        }
+        Ok(())
+    }
+}
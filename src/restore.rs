@@ -0,0 +1,137 @@
+/*
+ * Copyright 2021 Michael Krolikowski
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Result, anyhow};
+use rocksdb::backup::RestoreOptions;
+
+use crate::backup::{list_checkpoints, open_backup_engine};
+use crate::settings::{RestoreSettings, RestoreSource};
+
+pub fn is_empty_directory(directory: &Path) -> Result<bool> {
+    if !directory.exists() {
+        return Ok(true);
+    }
+    Ok(std::fs::read_dir(directory)?.next().is_none())
+}
+
+fn source_directory(config: &RestoreSettings) -> Result<&Path> {
+    config
+        .directory
+        .as_deref()
+        .map(Path::new)
+        .ok_or_else(|| anyhow!("rocksdb.restore.directory is required"))
+}
+
+fn restore_checkpoint(config: &RestoreSettings, directory: &Path) -> Result<bool> {
+    let (info, checkpoint) = match list_checkpoints(source_directory(config)?)?.pop() {
+        Some(latest) => latest,
+        None => return Ok(false),
+    };
+    log::info!("Restoring checkpoint {}", info.id);
+    std::fs::create_dir_all(directory)?;
+    for entry in std::fs::read_dir(checkpoint)? {
+        let entry = entry?;
+        std::fs::copy(entry.path(), directory.join(entry.file_name()))?;
+    }
+    Ok(true)
+}
+
+fn restore_backup(config: &RestoreSettings, directory: &Path) -> Result<bool> {
+    let source = source_directory(config)?;
+    if !source.exists() {
+        return Ok(false);
+    }
+    let mut engine = open_backup_engine(source)?;
+    if engine.get_backup_info().is_empty() {
+        return Ok(false);
+    }
+    log::info!("Restoring latest backup from {}", source.display());
+    engine.restore_from_latest_backup(directory, directory, &RestoreOptions::default())?;
+    Ok(true)
+}
+
+#[cfg(feature = "s3")]
+fn restore_s3(config: &RestoreSettings, directory: &Path) -> Result<bool> {
+    let s3 = config
+        .s3
+        .as_ref()
+        .ok_or_else(|| anyhow!("rocksdb.restore.s3 is required"))?;
+    // Database::new is synchronous, so the download runs on its own runtime.
+    let manifest = std::thread::scope(|scope| {
+        scope
+            .spawn(|| {
+                tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()?
+                    .block_on(crate::snapshot::download_latest(s3, directory))
+            })
+            .join()
+            .map_err(|_| anyhow!("Snapshot download panicked"))?
+    })?;
+    match manifest {
+        Some(manifest) => {
+            log::info!("Restored snapshot {}", manifest.id);
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+#[cfg(not(feature = "s3"))]
+fn restore_s3(_config: &RestoreSettings, _directory: &Path) -> Result<bool> {
+    Err(anyhow!("Restoring from S3 requires the s3 feature"))
+}
+
+/// Sibling of `directory` that a restore is written to before it is moved into place.
+fn staging_directory(directory: &Path) -> PathBuf {
+    let mut name = directory.as_os_str().to_owned();
+    name.push(".restoring");
+    PathBuf::from(name)
+}
+
+/// Restores the latest backup into `directory`.
+/// Returns `false` if there was nothing to restore.
+///
+/// The backup is restored into a staging directory first, which is only renamed to `directory`
+/// once it is complete, so a failed restore never leaves a partial database behind.
+pub fn restore(config: &RestoreSettings, directory: &Path) -> Result<bool> {
+    let staging = staging_directory(directory);
+    if staging.exists() {
+        std::fs::remove_dir_all(&staging)?;
+    }
+    let restored = match config.source {
+        RestoreSource::Checkpoint => restore_checkpoint(config, &staging),
+        RestoreSource::Backup => restore_backup(config, &staging),
+        RestoreSource::S3 => restore_s3(config, &staging),
+    };
+    match restored {
+        Ok(true) => {
+            if directory.exists() {
+                std::fs::remove_dir(directory)?;
+            }
+            std::fs::rename(&staging, directory)?;
+            Ok(true)
+        }
+        result => {
+            if staging.exists() {
+                std::fs::remove_dir_all(&staging)?;
+            }
+            result
+        }
+    }
+}
@@ -0,0 +1,246 @@
+/*
+ * Copyright 2021 Michael Krolikowski
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Secrets in the configuration: references to files and environment variables, which are
+//! resolved when reading the settings, and redaction of secret values in `Debug` output.
+
+use std::collections::BTreeSet;
+use std::fmt;
+
+use anyhow::{Context, Result, anyhow};
+use config::{Value, ValueKind};
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+const REDACTED: &str = "<redacted>";
+
+/// A value which is never printed.
+#[derive(Clone, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+/// Whether the librdkafka property `key` holds a secret, e.g. `sasl.password`.
+pub fn is_secret_property(key: &str) -> bool {
+    key.contains("password")
+        || key.contains("secret")
+        || key == "ssl.key.pem"
+        || key == "sasl.oauthbearer.config"
+}
+
+/// Debug representation of properties with the values of secret properties and of the
+/// `referenced` properties, whose values were read from references, redacted.
+pub fn redacted<'a, I>(properties: I, referenced: &BTreeSet<String>) -> impl fmt::Debug
+where
+    I: IntoIterator<Item = (&'a String, &'a String)>,
+{
+    struct Redacted<'a>(Vec<(&'a String, &'a str)>);
+
+    impl fmt::Debug for Redacted<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_map().entries(self.0.iter().copied()).finish()
+        }
+    }
+
+    Redacted(
+        properties
+            .into_iter()
+            .map(|(k, v)| {
+                if is_secret_property(k) || referenced.contains(k) {
+                    (k, REDACTED)
+                } else {
+                    (k, v.as_str())
+                }
+            })
+            .collect(),
+    )
+}
+
+/// Reads a secret from a file, without a trailing line break.
+pub fn read_file(path: &str) -> Result<String> {
+    let value = std::fs::read_to_string(path).with_context(|| format!("Failed to read {path}"))?;
+    Ok(value.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Replaces `${file:<path>}` by the content of the file and `${env:<name>}` by the value of
+/// the environment variable. `$${` is a literal `${`.
+pub fn resolve_references(value: &str) -> Result<String> {
+    Ok(resolve_string(value)?.0)
+}
+
+/// Like [`resolve_references`], additionally returning whether `value` contained a reference.
+fn resolve_string(value: &str) -> Result<(String, bool)> {
+    let mut referenced = false;
+    let mut resolved = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            resolved.push_str(&rest[..start - 1]);
+            resolved.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        resolved.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .map(|end| start + end)
+            .ok_or_else(|| anyhow!("Unterminated reference in {value}"))?;
+        let reference = &rest[start + 2..end];
+        let content = match reference.split_once(':') {
+            Some(("file", path)) => read_file(path)?,
+            Some(("env", name)) => std::env::var(name)
+                .with_context(|| format!("Environment variable {name} is not set"))?,
+            _ => return Err(anyhow!("Unknown reference ${{{reference}}}")),
+        };
+        referenced = true;
+        resolved.push_str(&content);
+        rest = &rest[end + 1..];
+    }
+    resolved.push_str(rest);
+    Ok((resolved, referenced))
+}
+
+/// Resolves references in all strings of a configuration and returns the paths of the strings
+/// containing references, e.g. `["kafka", "sasl.username"]`.
+pub fn resolve(value: &mut Value) -> Result<Vec<Vec<String>>> {
+    let mut referenced = vec![];
+    resolve_at(value, &mut vec![], &mut referenced)?;
+    Ok(referenced)
+}
+
+fn resolve_at(
+    value: &mut Value,
+    path: &mut Vec<String>,
+    referenced: &mut Vec<Vec<String>>,
+) -> Result<()> {
+    match value.kind {
+        ValueKind::String(ref mut s) => {
+            let (resolved, is_referenced) = resolve_string(s)?;
+            *s = resolved;
+            if is_referenced {
+                referenced.push(path.clone());
+            }
+        }
+        ValueKind::Table(ref mut table) => {
+            for (key, value) in table.iter_mut() {
+                path.push(key.clone());
+                resolve_at(value, path, referenced)?;
+                path.pop();
+            }
+        }
+        ValueKind::Array(ref mut array) => {
+            for (index, value) in array.iter_mut().enumerate() {
+                path.push(index.to_string());
+                resolve_at(value, path, referenced)?;
+                path.pop();
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    #[test]
+    fn resolves_references() -> Result<()> {
+        let directory = tempfile::tempdir()?;
+        let password = directory.path().join("password");
+        std::fs::write(&password, "secret\n")?;
+
+        let value = format!("user:${{file:{}}}@${{env:PATH}}", password.display());
+        assert_eq!(
+            resolve_references(&value)?,
+            format!("user:secret@{}", std::env::var("PATH")?)
+        );
+        assert_eq!(resolve_references("plain")?, "plain");
+        assert_eq!(resolve_references("$${env:PATH}")?, "${env:PATH}");
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_invalid_references() {
+        assert!(resolve_references("${env:PATH").is_err());
+        assert!(resolve_references("${vault:secret}").is_err());
+        assert!(resolve_references("${file:/does/not/exist}").is_err());
+        assert!(resolve_references("${env:KAFKA_ROCKSDB_UNSET_VARIABLE}").is_err());
+    }
+
+    #[test]
+    fn redacts_secret_properties() {
+        let properties = BTreeMap::from([
+            (
+                "bootstrap.servers".to_string(),
+                "localhost:9092".to_string(),
+            ),
+            ("sasl.password".to_string(), "secret".to_string()),
+        ]);
+        let debug = format!("{:?}", redacted(properties.iter(), &BTreeSet::new()));
+        assert_eq!(
+            debug,
+            r#"{"bootstrap.servers": "localhost:9092", "sasl.password": "<redacted>"}"#
+        );
+        assert_eq!(format!("{:?}", Secret("secret".to_string())), "<redacted>");
+    }
+
+    #[test]
+    fn redacts_referenced_properties() {
+        let properties = BTreeMap::from([
+            ("client.id".to_string(), "1".to_string()),
+            ("sasl.username".to_string(), "1".to_string()),
+        ]);
+        let referenced = BTreeSet::from(["sasl.username".to_string()]);
+        let debug = format!("{:?}", redacted(properties.iter(), &referenced));
+        assert_eq!(
+            debug,
+            r#"{"client.id": "1", "sasl.username": "<redacted>"}"#
+        );
+    }
+
+    #[test]
+    fn returns_paths_of_references() -> Result<()> {
+        let mut value = Value::from(config::Map::from([
+            ("plain".to_string(), Value::from("$${env:PATH}")),
+            (
+                "kafka".to_string(),
+                Value::from(config::Map::from([(
+                    "sasl.username".to_string(),
+                    Value::from("${env:PATH}"),
+                )])),
+            ),
+        ]));
+        assert_eq!(
+            resolve(&mut value)?,
+            vec![vec!["kafka".to_string(), "sasl.username".to_string()]]
+        );
+        Ok(())
+    }
+}
@@ -14,59 +14,862 @@
  * limitations under the License.
  */
 
-use anyhow::Result;
+use anyhow::{Context, Result, anyhow};
 use config::FileFormat;
+use schemars::JsonSchema;
 use serde::Deserialize;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::num::NonZeroUsize;
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
 
-#[derive(Debug, Deserialize)]
+use crate::secrets::{self, Secret};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ConsumerMode {
+    Group,
+    Assign,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum StartPosition {
+    Earliest,
+    Latest,
+    Offset(i64),
+    Timestamp(i64),
+}
+
+/// What to do if a message can't be written to RocksDB.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorPolicy {
+    /// Log the error and continue with the next message.
+    Skip,
+    /// Stop consuming and exit with the error.
+    Fail,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct ConsumerSettings {
+    pub mode: ConsumerMode,
+    pub start: StartPosition,
+    pub partitions: BTreeMap<String, BTreeMap<String, StartPosition>>,
+    pub until_end: bool,
+    pub on_error: ErrorPolicy,
+}
+
+impl Default for ConsumerSettings {
+    fn default() -> Self {
+        ConsumerSettings {
+            mode: ConsumerMode::Group,
+            start: StartPosition::Earliest,
+            partitions: BTreeMap::new(),
+            until_end: false,
+            on_error: ErrorPolicy::Skip,
+        }
+    }
+}
+
+impl ConsumerSettings {
+    pub fn start_position(&self, topic: &str, partition: i32) -> StartPosition {
+        self.partitions
+            .get(topic)
+            .and_then(|p| p.get(&partition.to_string()))
+            .copied()
+            .unwrap_or(self.start)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, JsonSchema)]
+pub struct ObjectStoreSettings {
+    pub bucket: String,
+    #[serde(default)]
+    pub prefix: String,
+    pub endpoint: Option<String>,
+    pub region: Option<String>,
+    pub access_key_id: Option<Secret>,
+    pub secret_access_key: Option<Secret>,
+    #[serde(default)]
+    pub allow_http: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum RestoreSource {
+    Checkpoint,
+    Backup,
+    S3,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, JsonSchema)]
+pub struct RestoreSettings {
+    pub source: RestoreSource,
+    pub directory: Option<String>,
+    pub s3: Option<ObjectStoreSettings>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, JsonSchema)]
 pub struct RocksDBSettings {
     pub directory: String,
+    pub restore: Option<RestoreSettings>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize, JsonSchema)]
 pub struct PrometheusExporterSettings {
     pub address: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct LibrdkafkaSettings {
+    pub debug: Vec<String>,
+    pub broker_down_log_interval: u64,
+}
+
+impl Default for LibrdkafkaSettings {
+    fn default() -> Self {
+        LibrdkafkaSettings {
+            debug: vec![],
+            broker_down_log_interval: 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for log::LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Off => log::LevelFilter::Off,
+            LogLevel::Error => log::LevelFilter::Error,
+            LogLevel::Warn => log::LevelFilter::Warn,
+            LogLevel::Info => log::LevelFilter::Info,
+            LogLevel::Debug => log::LevelFilter::Debug,
+            LogLevel::Trace => log::LevelFilter::Trace,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct LoggingSettings {
+    pub level: LogLevel,
+    /// Levels of log targets (and their children), e.g. `librdkafka` or `kafka_rocksdb::rebuild`.
+    pub targets: BTreeMap<String, LogLevel>,
+}
+
+impl Default for LoggingSettings {
+    fn default() -> Self {
+        LoggingSettings {
+            level: LogLevel::Info,
+            targets: BTreeMap::from([(String::from("librdkafka"), LogLevel::Debug)]),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct ReloadSettings {
+    /// Seconds between checks whether the configuration file changed. Without, only SIGHUP
+    /// reloads the configuration.
+    pub interval: Option<u64>,
+    /// Drop the column families and offsets of topics removed from `topics`.
+    pub drop_removed_topics: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum BackupMode {
+    Checkpoint,
+    Backup,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, JsonSchema)]
+pub struct BackupSettings {
+    pub directory: String,
+    #[serde(default = "BackupSettings::default_mode")]
+    pub mode: BackupMode,
+    pub interval: Option<u64>,
+    #[serde(default = "BackupSettings::default_retention")]
+    pub retention: NonZeroUsize,
+}
+
+impl BackupSettings {
+    fn default_mode() -> BackupMode {
+        BackupMode::Checkpoint
+    }
+
+    fn default_retention() -> NonZeroUsize {
+        NonZeroUsize::new(5).unwrap()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, JsonSchema)]
+pub struct SnapshotSettings {
+    pub s3: ObjectStoreSettings,
+    pub interval: u64,
+    #[serde(default = "SnapshotSettings::default_retention")]
+    pub retention: NonZeroUsize,
+    pub staging_directory: Option<String>,
+}
+
+impl SnapshotSettings {
+    fn default_retention() -> NonZeroUsize {
+        NonZeroUsize::new(5).unwrap()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, JsonSchema)]
+pub struct OpenTelemetrySettings {
+    pub endpoint: String,
+    #[serde(default = "OpenTelemetrySettings::default_service_name")]
+    pub service_name: String,
+}
+
+impl OpenTelemetrySettings {
+    fn default_service_name() -> String {
+        String::from("kafka-rocksdb")
+    }
+}
+
+/// librdkafka properties, whose secrets and values read from references are redacted in `Debug`
+/// output.
+#[derive(Clone, Default, PartialEq, Deserialize, JsonSchema)]
+#[serde(transparent)]
+pub struct KafkaProperties {
+    properties: BTreeMap<String, String>,
+    /// Properties whose values were read from `${file:}` or `${env:}` references.
+    #[serde(skip)]
+    referenced: BTreeSet<String>,
+}
+
+impl KafkaProperties {
+    /// Adds `other`, overriding properties set in both.
+    fn extend(&mut self, other: &KafkaProperties) {
+        for (key, value) in other.properties.iter() {
+            self.properties.insert(key.clone(), value.clone());
+            if other.referenced.contains(key) {
+                self.referenced.insert(key.clone());
+            } else {
+                self.referenced.remove(key);
+            }
+        }
+    }
+}
+
+impl Deref for KafkaProperties {
+    type Target = BTreeMap<String, String>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.properties
+    }
+}
+
+impl DerefMut for KafkaProperties {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.properties
+    }
+}
+
+impl fmt::Debug for KafkaProperties {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        secrets::redacted(self.properties.iter(), &self.referenced).fmt(f)
+    }
+}
+
+/// A pipeline consuming its own topics into its own RocksDB, see [`Settings::pipelines`].
+#[derive(Debug, Clone, PartialEq, Deserialize, JsonSchema)]
+pub struct PipelineSettings {
+    pub topics: Vec<String>,
+    /// librdkafka properties overriding the top-level ones, e.g. `group.id`.
+    #[serde(default)]
+    pub kafka: KafkaProperties,
+    /// Replaces the top-level consumer settings.
+    pub consumer: Option<ConsumerSettings>,
+    pub rocksdb: RocksDBSettings,
+    pub backup: Option<BackupSettings>,
+    /// Periodic snapshots to S3, requires the `s3` feature.
+    pub snapshots: Option<SnapshotSettings>,
+}
+
+const DEFAULT_PIPELINE: &str = "default";
+
+fn default_pipeline() -> String {
+    DEFAULT_PIPELINE.to_string()
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, JsonSchema)]
 pub struct Settings {
+    /// Topics to consume, each into a column family of the same name.
+    #[serde(default)]
     pub topics: Vec<String>,
-    pub kafka: BTreeMap<String, String>,
+    /// librdkafka properties.
+    pub kafka: KafkaProperties,
+    #[serde(default)]
+    pub librdkafka: LibrdkafkaSettings,
+    #[serde(default)]
+    pub consumer: ConsumerSettings,
+    #[serde(default)]
+    pub logging: LoggingSettings,
+    #[serde(default)]
+    pub reload: ReloadSettings,
+    #[serde(default)]
     pub rocksdb: RocksDBSettings,
+    /// HTTP server for metrics, admin and query endpoints.
     pub prometheus: PrometheusExporterSettings,
+    pub opentelemetry: Option<OpenTelemetrySettings>,
+    pub backup: Option<BackupSettings>,
+    /// Periodic snapshots to S3, requires the `s3` feature.
+    pub snapshots: Option<SnapshotSettings>,
+    /// Named pipelines replacing `topics`, `rocksdb`, `backup` and `snapshots`. Without
+    /// pipelines the top-level settings describe a single pipeline.
+    #[serde(default)]
+    pub pipelines: BTreeMap<String, PipelineSettings>,
+    /// Name of the pipeline these settings describe, used as label of the metrics.
+    #[serde(skip, default = "default_pipeline")]
+    pub pipeline: String,
 }
 
 impl Settings {
-    fn get_kafka_environment_settings() -> BTreeMap<String, String> {
-        let mut kafka_settings = BTreeMap::new();
-        for (ref k, v) in std::env::vars() {
-            if let Some(key) = k.strip_prefix("KR_KAFKA_") {
-                let key = key.replace('_', ".").to_lowercase();
-                kafka_settings.insert(key, v);
-            }
+    /// Settings of every pipeline, each combining the top-level settings with those of the
+    /// pipeline. Without `pipelines` these are the top-level settings named `default`.
+    pub fn pipelines(&self) -> Vec<Settings> {
+        if self.pipelines.is_empty() {
+            return vec![self.clone()];
         }
-        kafka_settings
+        self.pipelines
+            .iter()
+            .map(|(name, pipeline)| {
+                let mut settings = self.clone();
+                settings.pipelines = BTreeMap::new();
+                settings.pipeline = name.clone();
+                settings.topics = pipeline.topics.clone();
+                settings.kafka.extend(&pipeline.kafka);
+                if let Some(ref consumer) = pipeline.consumer {
+                    settings.consumer = consumer.clone();
+                }
+                settings.rocksdb = pipeline.rocksdb.clone();
+                settings.backup = pipeline.backup.clone();
+                settings.snapshots = pipeline.snapshots.clone();
+                settings
+            })
+            .collect()
     }
 
-    fn override_kafka_settings(&mut self, kafka_settings: BTreeMap<String, String>) {
-        for (k, v) in kafka_settings {
-            self.kafka.insert(k, v);
+    /// Settings of the pipeline `name`, which may be omitted if there's only one.
+    pub fn pipeline(&self, name: Option<&str>) -> Result<Settings> {
+        let mut pipelines = self.pipelines();
+        match name {
+            Some(name) => pipelines
+                .into_iter()
+                .find(|p| p.pipeline == name)
+                .ok_or_else(|| anyhow!("Unknown pipeline {name}")),
+            None if pipelines.len() == 1 => Ok(pipelines.remove(0)),
+            None => Err(anyhow!(
+                "Select one of the pipelines {}",
+                pipelines
+                    .iter()
+                    .map(|p| p.pipeline.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )),
         }
     }
 
+    /// Reads the settings from `filename` and the environment, resolving `${file:<path>}` and
+    /// `${env:<name>}` references in all strings.
     pub fn read(filename: &str) -> Result<Settings> {
-        let kafka_settings = Settings::get_kafka_environment_settings();
-        let config = config::Config::builder()
-            .add_source(
-                config::File::with_name(filename)
-                    .format(FileFormat::Toml)
-                    .required(false),
-            )
-            .add_source(config::Environment::with_prefix("KR").separator("_"))
-            .build()?;
-        let mut settings: Settings = config.try_deserialize()?;
-        settings.override_kafka_settings(kafka_settings);
+        Settings::read_sources(&SettingsSources::file(filename))
+    }
+
+    /// Reads the settings from all `sources`, see [`SettingsSources`].
+    pub fn read_sources(sources: &SettingsSources) -> Result<Settings> {
+        let (settings, unknown) = Settings::read_reporting_unknown(sources)?;
+        for key in unknown {
+            log::warn!("Ignoring unknown setting {key}");
+        }
         Ok(settings)
     }
+
+    /// Like [`Settings::read_sources`], additionally returning the keys which aren't settings.
+    pub fn read_reporting_unknown(sources: &SettingsSources) -> Result<(Settings, Vec<String>)> {
+        let mut builder = config::Config::builder();
+        for (index, file) in sources.files.iter().enumerate() {
+            builder = builder.add_source(
+                config::File::with_name(file)
+                    .format(file_format(file))
+                    .required(index > 0),
+            );
+        }
+        if !sources.overrides.is_empty() {
+            let overrides = parse_overrides(&sources.overrides)?;
+            builder = builder.add_source(config::File::from_str(
+                &overrides.to_string(),
+                FileFormat::Json,
+            ));
+        }
+        let environment = environment_settings(std::env::vars())?;
+        let config = builder
+            .add_source(config::File::from_str(
+                &environment.to_string(),
+                FileFormat::Json,
+            ))
+            .build()?;
+        let mut value: config::Value = config.try_deserialize()?;
+        let referenced = secrets::resolve(&mut value)?;
+        let mut unknown = vec![];
+        let mut settings: Settings =
+            serde_ignored::deserialize(value, |path| unknown.push(path.to_string()))?;
+        for path in referenced {
+            settings.mark_referenced(&path);
+        }
+        Ok((settings, unknown))
+    }
+
+    /// Marks the Kafka property at `path` as read from a reference, to redact it.
+    fn mark_referenced(&mut self, path: &[String]) {
+        let kafka = match path {
+            [kafka, _] if kafka == "kafka" => &mut self.kafka,
+            [pipelines, name, kafka, _] if pipelines == "pipelines" && kafka == "kafka" => {
+                match self.pipelines.get_mut(name) {
+                    Some(pipeline) => &mut pipeline.kafka,
+                    None => return,
+                }
+            }
+            _ => return,
+        };
+        if let Some(key) = path.last() {
+            kafka.referenced.insert(key.clone());
+        }
+    }
+}
+
+/// Where to read the settings from, in increasing order of precedence: the configuration
+/// `files`, the `key=value` `overrides` and finally the `KR_` and `KR_KAFKA_` environment
+/// variables. Only the first file may be missing, in favour of the environment variables.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SettingsSources {
+    pub files: Vec<String>,
+    pub overrides: Vec<String>,
+}
+
+impl SettingsSources {
+    pub fn file(filename: &str) -> Self {
+        SettingsSources {
+            files: vec![filename.to_string()],
+            overrides: vec![],
+        }
+    }
+}
+
+/// Format of a configuration file by its extension, TOML unless it's `.yaml`, `.yml` or `.json`.
+fn file_format(filename: &str) -> FileFormat {
+    let extension = Path::new(filename)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase());
+    match extension.as_deref() {
+        Some("yaml" | "yml") => FileFormat::Yaml,
+        Some("json") => FileFormat::Json,
+        _ => FileFormat::Toml,
+    }
+}
+
+/// Joins the segments following `kafka` (or `pipelines.<name>.kafka`) into a single property,
+/// as Kafka properties contain dots.
+fn join_kafka_property(mut path: Vec<String>) -> Vec<String> {
+    let index = match path.as_slice() {
+        [first, ..] if first == "kafka" => 1,
+        [first, _, third, ..] if first == "pipelines" && third == "kafka" => 3,
+        _ => return path,
+    };
+    if path.len() > index {
+        let property = path[index..].join(".");
+        path.truncate(index);
+        path.push(property);
+    }
+    path
+}
+
+/// Splits the key of an override into its path. Segments are separated by `.` and may be
+/// quoted, e.g. `consumer.partitions."my.topic".0`. Everything after `kafka.` is a single
+/// property.
+fn override_path(key: &str) -> Result<Vec<String>> {
+    let mut path = vec![];
+    let mut rest = key;
+    while !rest.is_empty() {
+        let (segment, remainder) = match rest.strip_prefix('"') {
+            Some(quoted) => {
+                let end = quoted
+                    .find('"')
+                    .ok_or_else(|| anyhow!("Unterminated quote in {key}"))?;
+                (&quoted[..end], &quoted[end + 1..])
+            }
+            None => rest.split_at(rest.find('.').unwrap_or(rest.len())),
+        };
+        if segment.is_empty() {
+            return Err(anyhow!("Empty segment in {key}"));
+        }
+        path.push(segment.to_string());
+        rest = match remainder.strip_prefix('.') {
+            Some(rest) if !rest.is_empty() => rest,
+            None if remainder.is_empty() => remainder,
+            _ => return Err(anyhow!("Invalid key {key}")),
+        };
+    }
+    if path.is_empty() {
+        return Err(anyhow!("Empty key"));
+    }
+    Ok(join_kafka_property(path))
+}
+
+/// Builds a document of `key=value` overrides. Values are parsed as JSON, falling back to
+/// plain strings, so `consumer.until_end=true` and `topics=["a","b"]` work as expected.
+fn parse_overrides(overrides: &[String]) -> Result<serde_json::Value> {
+    let mut document = serde_json::Value::Object(Default::default());
+    for entry in overrides {
+        let (key, value) = entry
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Expected key=value, got {entry}"))?;
+        let value = serde_json::from_str(value)
+            .unwrap_or_else(|_| serde_json::Value::String(value.to_string()));
+        insert(&mut document, &override_path(key.trim())?, value)
+            .with_context(|| format!("{key} conflicts with another override"))?;
+    }
+    Ok(document)
+}
+
+/// Inserts `value` at `path` into `document`, creating the intermediate objects.
+fn insert(
+    document: &mut serde_json::Value,
+    path: &[String],
+    value: serde_json::Value,
+) -> Result<()> {
+    let (last, parents) = path.split_last().ok_or_else(|| anyhow!("Empty key"))?;
+    let mut current = document;
+    for segment in parents {
+        current = current
+            .as_object_mut()
+            .ok_or_else(|| anyhow!("{segment} isn't a table"))?
+            .entry(segment.as_str())
+            .or_insert_with(|| serde_json::Value::Object(Default::default()));
+    }
+    current
+        .as_object_mut()
+        .ok_or_else(|| anyhow!("{last} isn't a table"))?
+        .insert(last.clone(), value);
+    Ok(())
+}
+
+/// Splits an environment variable name into its lowercase path segments. A single `_`
+/// separates segments and `__` is a literal underscore, e.g. `CONSUMER_UNTIL__END` is
+/// `consumer.until_end`. Keys needing upper case letters can't be expressed.
+fn environment_path(name: &str) -> Option<Vec<String>> {
+    let mut segments = vec![String::new()];
+    let mut chars = name.chars().peekable();
+    while let Some(c) = chars.next() {
+        let segment = segments.last_mut()?;
+        match c {
+            '_' if chars.peek() == Some(&'_') => {
+                chars.next();
+                segment.push('_');
+            }
+            '_' => segments.push(String::new()),
+            c => segment.extend(c.to_lowercase()),
+        }
+    }
+    (!segments.iter().any(|s| s.is_empty())).then_some(segments)
+}
+
+/// Builds a document of the `KR_` environment variables, e.g. `KR_ROCKSDB_DIRECTORY` sets
+/// `rocksdb.directory`. The rest of `KR_KAFKA_` variables is a single Kafka property with `_`
+/// replaced by `.`, e.g. `KR_KAFKA_SASL_OAUTHBEARER_CLIENT_ID` sets
+/// `sasl.oauthbearer.client.id`, likewise for `KR_PIPELINES_<NAME>_KAFKA_`. See
+/// [`environment_path`] for underscores.
+fn environment_settings(vars: impl Iterator<Item = (String, String)>) -> Result<serde_json::Value> {
+    let mut document = serde_json::Value::Object(Default::default());
+    let vars: BTreeMap<String, String> = vars.collect();
+    for (name, value) in vars {
+        let Some(key) = name.strip_prefix("KR_") else {
+            continue;
+        };
+        let Some(path) = environment_path(key) else {
+            log::warn!("Ignoring environment variable {name} with an empty segment");
+            continue;
+        };
+        insert(
+            &mut document,
+            &join_kafka_property(path),
+            serde_json::Value::String(value),
+        )
+        .with_context(|| format!("{name} conflicts with another environment variable"))?;
+    }
+    Ok(document)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn detects_file_formats() {
+        assert_eq!(file_format("config.toml"), FileFormat::Toml);
+        assert_eq!(file_format("config"), FileFormat::Toml);
+        assert_eq!(
+            file_format("/etc/kafka-rocksdb/config.YAML"),
+            FileFormat::Yaml
+        );
+        assert_eq!(file_format("config.yml"), FileFormat::Yaml);
+        assert_eq!(file_format("config.json"), FileFormat::Json);
+    }
+
+    #[test]
+    fn parses_overrides() -> Result<()> {
+        let overrides = [
+            "kafka.bootstrap.servers=localhost:9092",
+            "consumer.until_end=true",
+            r#"consumer.partitions."my.topic".0=latest"#,
+            r#"topics=["a", "b"]"#,
+            "rocksdb.directory=/data",
+            "pipelines.orders.kafka.group.id=orders",
+        ]
+        .map(String::from);
+        assert_eq!(
+            parse_overrides(&overrides)?,
+            json!({
+                "kafka": {"bootstrap.servers": "localhost:9092"},
+                "consumer": {"until_end": true, "partitions": {"my.topic": {"0": "latest"}}},
+                "topics": ["a", "b"],
+                "rocksdb": {"directory": "/data"},
+                "pipelines": {"orders": {"kafka": {"group.id": "orders"}}},
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_invalid_overrides() {
+        for entry in ["topics", "=x", "a..b=1", r#""a=1"#, "a=1"] {
+            let overrides = vec![entry.to_string(), "a.b=1".to_string()];
+            assert!(parse_overrides(&overrides).is_err(), "{entry}");
+        }
+    }
+
+    fn environment(vars: &[(&str, &str)]) -> Result<serde_json::Value> {
+        environment_settings(
+            vars.iter()
+                .map(|(name, value)| (name.to_string(), value.to_string())),
+        )
+    }
+
+    #[test]
+    fn splits_environment_variables() {
+        let path = |name| environment_path(name).map(|p| p.join("."));
+        assert_eq!(
+            path("ROCKSDB_DIRECTORY").as_deref(),
+            Some("rocksdb.directory")
+        );
+        assert_eq!(
+            path("CONSUMER_UNTIL__END").as_deref(),
+            Some("consumer.until_end")
+        );
+        assert_eq!(
+            path("RELOAD_DROP__REMOVED__TOPICS").as_deref(),
+            Some("reload.drop_removed_topics")
+        );
+        assert_eq!(path("A___B").as_deref(), Some("a_.b"));
+        assert_eq!(path("TOPICS").as_deref(), Some("topics"));
+        assert_eq!(path("ROCKSDB_"), None);
+        assert_eq!(path("_ROCKSDB"), None);
+        assert_eq!(path(""), None);
+    }
+
+    #[test]
+    fn maps_environment_variables() -> Result<()> {
+        assert_eq!(
+            environment(&[
+                ("KR_KAFKA_BOOTSTRAP_SERVERS", "localhost:9092"),
+                ("KR_KAFKA_SASL_OAUTHBEARER_CLIENT_ID", "client"),
+                ("KR_KAFKA_SOME__PROPERTY_NAME", "x"),
+                ("KR_ROCKSDB_DIRECTORY", "/data"),
+                ("KR_CONSUMER_UNTIL__END", "true"),
+                ("KR_CONSUMER_PARTITIONS_MY__TOPIC_0", "latest"),
+                ("KR_PIPELINES_ORDERS_KAFKA_GROUP_ID", "orders"),
+                ("PATH", "/bin"),
+                ("KR_", "ignored"),
+            ])?,
+            json!({
+                "kafka": {
+                    "bootstrap.servers": "localhost:9092",
+                    "sasl.oauthbearer.client.id": "client",
+                    "some_property.name": "x",
+                },
+                "rocksdb": {"directory": "/data"},
+                "consumer": {"until_end": "true", "partitions": {"my_topic": {"0": "latest"}}},
+                "pipelines": {"orders": {"kafka": {"group.id": "orders"}}},
+            })
+        );
+        assert!(environment(&[("KR_ROCKSDB", "x"), ("KR_ROCKSDB_DIRECTORY", "y")]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn layers_files_and_overrides() -> Result<()> {
+        let directory = tempfile::tempdir()?;
+        let base = directory.path().join("base.toml");
+        std::fs::write(
+            &base,
+            "topics = [\"a\"]\n[kafka]\n\"group.id\" = \"base\"\n\"client.id\" = \"base\"\n",
+        )?;
+        let overlay = directory.path().join("overlay.yaml");
+        std::fs::write(
+            &overlay,
+            "kafka:\n  group.id: overlay\nrocksdb:\n  directory: /tmp/db\n",
+        )?;
+        let sources = SettingsSources {
+            files: vec![
+                base.to_string_lossy().to_string(),
+                overlay.to_string_lossy().to_string(),
+            ],
+            overrides: vec![
+                "kafka.client.id=cli".to_string(),
+                "prometheus.address=127.0.0.1:0".to_string(),
+            ],
+        };
+        let settings = Settings::read_sources(&sources)?;
+        assert_eq!(settings.topics, vec!["a".to_string()]);
+        assert_eq!(settings.kafka["group.id"], "overlay");
+        assert_eq!(settings.kafka["client.id"], "cli");
+        assert_eq!(settings.rocksdb.directory, "/tmp/db");
+
+        let missing = SettingsSources {
+            files: vec![
+                base.to_string_lossy().to_string(),
+                directory
+                    .path()
+                    .join("missing.yaml")
+                    .to_string_lossy()
+                    .to_string(),
+            ],
+            overrides: vec![],
+        };
+        assert!(Settings::read_sources(&missing).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn combines_pipelines_with_top_level_settings() -> Result<()> {
+        let directory = tempfile::tempdir()?;
+        let config = directory.path().join("config.toml");
+        std::fs::write(
+            &config,
+            r#"
+[kafka]
+"bootstrap.servers" = "localhost:9092"
+"group.id" = "shared"
+
+[consumer]
+"mode" = "assign"
+
+[prometheus]
+"address" = "127.0.0.1:0"
+
+[pipelines.orders]
+topics = ["orders"]
+kafka = { "group.id" = "orders" }
+rocksdb = { directory = "/data/orders" }
+consumer = { on_error = "fail" }
+
+[pipelines.users]
+topics = ["users"]
+rocksdb = { directory = "/data/users" }
+"#,
+        )?;
+        let settings = Settings::read(&config.to_string_lossy())?;
+        assert_eq!(settings.pipelines().len(), 2);
+
+        let orders = settings.pipeline(Some("orders"))?;
+        assert_eq!(orders.pipeline, "orders");
+        assert_eq!(orders.topics, vec!["orders".to_string()]);
+        assert_eq!(orders.kafka["bootstrap.servers"], "localhost:9092");
+        assert_eq!(orders.kafka["group.id"], "orders");
+        assert_eq!(orders.consumer.mode, ConsumerMode::Group);
+        assert_eq!(orders.consumer.on_error, ErrorPolicy::Fail);
+        assert_eq!(orders.rocksdb.directory, "/data/orders");
+
+        let users = settings.pipeline(Some("users"))?;
+        assert_eq!(users.kafka["group.id"], "shared");
+        assert_eq!(users.consumer.mode, ConsumerMode::Assign);
+        assert!(users.pipelines.is_empty());
+
+        assert!(settings.pipeline(None).is_err());
+        assert!(settings.pipeline(Some("unknown")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_zero_retention() -> Result<()> {
+        let directory = tempfile::tempdir()?;
+        let config = directory.path().join("config.toml");
+        std::fs::write(
+            &config,
+            "[backup]\n\"directory\" = \"./backups\"\n\"retention\" = 0\n",
+        )?;
+        assert!(Settings::read(&config.to_string_lossy()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn redacts_referenced_kafka_properties() -> Result<()> {
+        let directory = tempfile::tempdir()?;
+        let username = directory.path().join("username");
+        std::fs::write(&username, "9092\n")?;
+        let config = directory.path().join("config.toml");
+        std::fs::write(
+            &config,
+            format!(
+                r#"
+[kafka]
+"bootstrap.servers" = "localhost:9092"
+"sasl.username" = "${{file:{}}}"
+
+[prometheus]
+"address" = "127.0.0.1:0"
+
+[pipelines.orders]
+topics = ["orders"]
+kafka = {{ "sasl.username" = "orders" }}
+rocksdb = {{ directory = "/data/orders" }}
+"#,
+                username.display()
+            ),
+        )?;
+        let settings = Settings::read(&config.to_string_lossy())?;
+        assert_eq!(settings.kafka["sasl.username"], "9092");
+        assert_eq!(
+            format!("{:?}", settings.kafka),
+            r#"{"bootstrap.servers": "localhost:9092", "sasl.username": "<redacted>"}"#
+        );
+        let orders = settings.pipeline(Some("orders"))?;
+        assert_eq!(
+            format!("{:?}", orders.kafka),
+            r#"{"bootstrap.servers": "localhost:9092", "sasl.username": "orders"}"#
+        );
+        Ok(())
+    }
 }
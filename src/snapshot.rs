@@ -0,0 +1,268 @@
+/*
+ * Copyright 2021 Michael Krolikowski
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Snapshots in an S3-compatible object store.
+//!
+//! A snapshot is stored as `<prefix>/<id>/<file>` for every file of a RocksDB checkpoint,
+//! followed by `<prefix>/<id>/manifest.json`. The manifest is written last, so only
+//! snapshots with a manifest are complete.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Result, anyhow};
+use bytes::Bytes;
+use futures::TryStreamExt;
+use object_store::aws::{AmazonS3, AmazonS3Builder};
+use object_store::buffered::BufWriter;
+use object_store::path::Path as ObjectPath;
+use object_store::{ObjectStore, ObjectStoreExt, PutPayload};
+use prometheus::IntCounter;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::database::Database;
+use crate::offsets::Offsets;
+use crate::settings::{ObjectStoreSettings, SnapshotSettings};
+
+const CHUNK_SIZE: usize = 8 * 1024 * 1024;
+
+pub const MANIFEST: &str = "manifest.json";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SnapshotFile {
+    pub name: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub id: String,
+    /// Creation time in milliseconds since the epoch.
+    pub timestamp: i64,
+    pub offsets: Offsets,
+    pub files: Vec<SnapshotFile>,
+}
+
+pub fn object_store(config: &ObjectStoreSettings) -> Result<AmazonS3> {
+    let mut builder = AmazonS3Builder::from_env()
+        .with_bucket_name(&config.bucket)
+        .with_allow_http(config.allow_http);
+    if let Some(ref endpoint) = config.endpoint {
+        builder = builder.with_endpoint(endpoint);
+    }
+    if let Some(ref region) = config.region {
+        builder = builder.with_region(region);
+    }
+    if let Some(ref access_key_id) = config.access_key_id {
+        builder = builder.with_access_key_id(access_key_id.expose());
+    }
+    if let Some(ref secret_access_key) = config.secret_access_key {
+        builder = builder.with_secret_access_key(secret_access_key.expose());
+    }
+    Ok(builder.build()?)
+}
+
+pub fn object_path(config: &ObjectStoreSettings, parts: &[&str]) -> ObjectPath {
+    config
+        .prefix
+        .split('/')
+        .chain(parts.iter().copied())
+        .filter(|p| !p.is_empty())
+        .collect()
+}
+
+/// Lists all complete snapshots, oldest first.
+pub async fn list_manifests(
+    store: &impl ObjectStore,
+    config: &ObjectStoreSettings,
+) -> Result<Vec<Manifest>> {
+    let prefix = object_path(config, &[]);
+    let objects: Vec<_> = store.list(Some(&prefix)).try_collect().await?;
+    let mut manifests = vec![];
+    for object in objects {
+        if object.location.filename() == Some(MANIFEST) {
+            let data = store.get(&object.location).await?.bytes().await?;
+            manifests.push(serde_json::from_slice::<Manifest>(&data)?);
+        }
+    }
+    manifests.sort_by_key(|m| m.timestamp);
+    Ok(manifests)
+}
+
+/// Downloads the latest complete snapshot into `directory`.
+pub async fn download_latest(
+    config: &ObjectStoreSettings,
+    directory: &Path,
+) -> Result<Option<Manifest>> {
+    let store = object_store(config)?;
+    let manifest = match list_manifests(&store, config).await?.pop() {
+        Some(manifest) => manifest,
+        None => return Ok(None),
+    };
+    tokio::fs::create_dir_all(directory).await?;
+    for file in manifest.files.iter() {
+        let location = object_path(config, &[&manifest.id, &file.name]);
+        let mut stream = store.get(&location).await?.into_stream();
+        let mut output = tokio::fs::File::create(directory.join(&file.name)).await?;
+        let mut hasher = Sha256::new();
+        while let Some(chunk) = stream.try_next().await? {
+            hasher.update(&chunk);
+            output.write_all(&chunk).await?;
+        }
+        output.flush().await?;
+        if format!("{:x}", hasher.finalize()) != file.sha256 {
+            return Err(anyhow!("Checksum mismatch for {location}"));
+        }
+    }
+    Ok(Some(manifest))
+}
+
+pub struct SnapshotUploader {
+    db: Arc<Database>,
+    config: SnapshotSettings,
+    staging_directory: PathBuf,
+    store: Arc<AmazonS3>,
+    uploaded: IntCounter,
+}
+
+impl SnapshotUploader {
+    pub fn new(
+        db: Arc<Database>,
+        config: &SnapshotSettings,
+        rocksdb_directory: &str,
+        pipeline: &str,
+    ) -> Result<SnapshotUploader> {
+        let staging_directory = match config.staging_directory {
+            Some(ref directory) => PathBuf::from(directory),
+            None => PathBuf::from(format!("{rocksdb_directory}.snapshots")),
+        };
+        Ok(SnapshotUploader {
+            db,
+            store: Arc::new(object_store(&config.s3)?),
+            config: config.clone(),
+            staging_directory,
+            uploaded: crate::metrics::SNAPSHOTS.with_label_values(&[pipeline]),
+        })
+    }
+
+    /// Creates a checkpoint, uploads it together with its offsets and removes obsolete snapshots.
+    pub async fn upload(&self) -> Result<Manifest> {
+        let timestamp = chrono::Utc::now().timestamp_millis();
+        let id = format!("snapshot-{timestamp}");
+        let checkpoint = self.staging_directory.join(&id);
+        tokio::fs::create_dir_all(&self.staging_directory).await?;
+        let db = self.db.clone();
+        let path = checkpoint.clone();
+        tokio::task::spawn_blocking(move || db.create_checkpoint(&path)).await??;
+        let manifest = self.upload_checkpoint(id, timestamp, &checkpoint).await;
+        tokio::fs::remove_dir_all(&checkpoint).await?;
+        let manifest = manifest?;
+        self.purge().await?;
+        Ok(manifest)
+    }
+
+    async fn upload_file(&self, location: ObjectPath, path: &Path) -> Result<SnapshotFile> {
+        let mut file = tokio::fs::File::open(path).await?;
+        let mut writer = BufWriter::new(self.store.clone(), location);
+        let mut hasher = Sha256::new();
+        let mut size = 0;
+        loop {
+            let mut chunk = vec![0; CHUNK_SIZE];
+            let read = file.read(&mut chunk).await?;
+            if read == 0 {
+                break;
+            }
+            chunk.truncate(read);
+            hasher.update(&chunk);
+            size += read as u64;
+            writer.put(Bytes::from(chunk)).await?;
+        }
+        writer.shutdown().await?;
+        Ok(SnapshotFile {
+            name: path
+                .file_name()
+                .map(|n| n.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            size,
+            sha256: format!("{:x}", hasher.finalize()),
+        })
+    }
+
+    async fn upload_checkpoint(
+        &self,
+        id: String,
+        timestamp: i64,
+        checkpoint: &Path,
+    ) -> Result<Manifest> {
+        let path = checkpoint.to_path_buf();
+        let offsets = tokio::task::spawn_blocking(move || Offsets::read(&path)).await??;
+        let mut files = vec![];
+        let mut entries = tokio::fs::read_dir(checkpoint).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let location = object_path(&self.config.s3, &[&id, &name]);
+            files.push(self.upload_file(location, &entry.path()).await?);
+        }
+        let manifest = Manifest {
+            id,
+            timestamp,
+            offsets,
+            files,
+        };
+        let location = object_path(&self.config.s3, &[&manifest.id, MANIFEST]);
+        self.store
+            .put(
+                &location,
+                PutPayload::from(serde_json::to_vec_pretty(&manifest)?),
+            )
+            .await?;
+        self.uploaded.inc();
+        Ok(manifest)
+    }
+
+    async fn purge(&self) -> Result<()> {
+        let manifests = list_manifests(self.store.as_ref(), &self.config.s3).await?;
+        let obsolete = manifests.len().saturating_sub(self.config.retention.get());
+        for manifest in manifests.into_iter().take(obsolete) {
+            log::info!("Removing snapshot {}", manifest.id);
+            self.store
+                .delete(&object_path(&self.config.s3, &[&manifest.id, MANIFEST]))
+                .await?;
+            let prefix = object_path(&self.config.s3, &[&manifest.id]);
+            let objects: Vec<_> = self.store.list(Some(&prefix)).try_collect().await?;
+            for object in objects {
+                self.store.delete(&object.location).await?;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn schedule(self: Arc<Self>) -> Result<()> {
+        let mut interval = tokio::time::interval(Duration::from_secs(self.config.interval));
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            match self.upload().await {
+                Ok(manifest) => log::info!("Uploaded snapshot {}", manifest.id),
+                Err(e) => log::error!("Failed to upload snapshot: {e}"),
+            }
+        }
+    }
+}
@@ -0,0 +1,79 @@
+/*
+ * Copyright 2021 Michael Krolikowski
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Read access shared by the database of the running pipeline and secondary instances.
+
+use anyhow::Result;
+use rocksdb::{Direction, IteratorMode};
+
+use crate::database::Snapshot;
+use crate::offsets::Offsets;
+use crate::reader::Reader;
+
+/// A consistent view of the data and offsets of a store.
+pub trait Store {
+    fn offsets(&self) -> Result<Offsets>;
+
+    /// Calls `f` for every record of `topic` whose key starts with `prefix`, in key order.
+    fn for_each(
+        &self,
+        topic: &str,
+        prefix: &[u8],
+        f: &mut dyn FnMut(&[u8], &[u8]) -> Result<()>,
+    ) -> Result<()>;
+}
+
+impl Store for Snapshot<'_> {
+    fn offsets(&self) -> Result<Offsets> {
+        Snapshot::offsets(self)
+    }
+
+    fn for_each(
+        &self,
+        topic: &str,
+        prefix: &[u8],
+        f: &mut dyn FnMut(&[u8], &[u8]) -> Result<()>,
+    ) -> Result<()> {
+        for row in self.iterator(topic, IteratorMode::From(prefix, Direction::Forward))? {
+            let (key, value) = row?;
+            if !key.starts_with(prefix) {
+                break;
+            }
+            f(&key, &value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Only consistent if the reader doesn't catch up with the primary meanwhile, i.e. it was
+/// opened without a catch-up interval.
+impl Store for Reader {
+    fn offsets(&self) -> Result<Offsets> {
+        Reader::offsets(self)
+    }
+
+    fn for_each(
+        &self,
+        topic: &str,
+        prefix: &[u8],
+        f: &mut dyn FnMut(&[u8], &[u8]) -> Result<()>,
+    ) -> Result<()> {
+        Reader::for_each(self, topic, prefix, |key, value| {
+            f(key, value)?;
+            Ok(true)
+        })
+    }
+}
@@ -0,0 +1,210 @@
+/*
+ * Copyright 2021 Michael Krolikowski
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::fmt::Display;
+
+use anyhow::Result;
+use rdkafka::Message;
+
+use crate::settings::Settings;
+
+#[cfg(feature = "opentelemetry")]
+mod otel {
+    use std::fmt::Display;
+
+    use anyhow::Result;
+    use opentelemetry::propagation::Extractor;
+    use opentelemetry::trace::{SpanKind, Status, TraceContextExt, Tracer};
+    use opentelemetry::{Context, KeyValue, global};
+    use opentelemetry_otlp::WithExportConfig;
+    use opentelemetry_sdk::Resource;
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use rdkafka::Message;
+    use rdkafka::message::{BorrowedHeaders, Headers};
+
+    use crate::settings::OpenTelemetrySettings;
+
+    pub struct Telemetry {
+        provider: SdkTracerProvider,
+    }
+
+    impl Telemetry {
+        pub fn new(config: &OpenTelemetrySettings) -> Result<Telemetry> {
+            let exporter = opentelemetry_otlp::SpanExporter::builder()
+                .with_http()
+                .with_endpoint(&config.endpoint)
+                .build()?;
+            let resource = Resource::builder()
+                .with_service_name(config.service_name.clone())
+                .build();
+            let provider = SdkTracerProvider::builder()
+                .with_resource(resource)
+                .with_batch_exporter(exporter)
+                .build();
+            global::set_text_map_propagator(TraceContextPropagator::new());
+            global::set_tracer_provider(provider.clone());
+            Ok(Telemetry { provider })
+        }
+    }
+
+    impl Drop for Telemetry {
+        fn drop(&mut self) {
+            if let Err(e) = self.provider.shutdown() {
+                log::warn!("Failed to shut down OpenTelemetry: {e}");
+            }
+        }
+    }
+
+    struct HeaderExtractor<'a>(&'a BorrowedHeaders);
+
+    impl Extractor for HeaderExtractor<'_> {
+        fn get(&self, key: &str) -> Option<&str> {
+            self.0
+                .iter()
+                .find(|h| h.key.eq_ignore_ascii_case(key))
+                .and_then(|h| h.value)
+                .and_then(|v| std::str::from_utf8(v).ok())
+        }
+
+        fn keys(&self) -> Vec<&str> {
+            self.0.iter().map(|h| h.key).collect()
+        }
+    }
+
+    fn parent_context<M: Message<Headers = BorrowedHeaders>>(msg: &M) -> Context {
+        match msg.headers() {
+            Some(headers) => global::get_text_map_propagator(|propagator| {
+                propagator.extract(&HeaderExtractor(headers))
+            }),
+            None => Context::new(),
+        }
+    }
+
+    pub fn in_message_span<M, T, E, F>(name: &'static str, msg: &M, f: F) -> Result<T, E>
+    where
+        M: Message<Headers = BorrowedHeaders>,
+        E: Display,
+        F: FnOnce() -> Result<T, E>,
+    {
+        let tracer = global::tracer("kafka-rocksdb");
+        let parent = parent_context(msg);
+        let span = tracer
+            .span_builder(name)
+            .with_kind(SpanKind::Consumer)
+            .with_attributes([
+                KeyValue::new("messaging.system", "kafka"),
+                KeyValue::new("messaging.operation", "process"),
+                KeyValue::new("messaging.destination.name", msg.topic().to_string()),
+                KeyValue::new(
+                    "messaging.destination.partition.id",
+                    msg.partition().to_string(),
+                ),
+                KeyValue::new("messaging.kafka.offset", msg.offset()),
+            ])
+            .start_with_context(&tracer, &parent);
+        let cx = parent.with_span(span);
+        let _guard = cx.clone().attach();
+        let result = f();
+        if let Err(ref e) = result {
+            cx.span().set_status(Status::error(e.to_string()));
+        }
+        result
+    }
+
+    pub fn in_span<T, E, F>(name: &'static str, f: F) -> Result<T, E>
+    where
+        E: Display,
+        F: FnOnce() -> Result<T, E>,
+    {
+        global::tracer("kafka-rocksdb").in_span(name, |cx| {
+            let result = f();
+            if let Err(ref e) = result {
+                cx.span().set_status(Status::error(e.to_string()));
+            }
+            result
+        })
+    }
+}
+
+/// Keeps the OpenTelemetry exporter alive and flushes pending spans when dropped.
+pub struct Telemetry {
+    #[cfg(feature = "opentelemetry")]
+    _otel: Option<otel::Telemetry>,
+}
+
+impl Telemetry {
+    #[cfg(feature = "opentelemetry")]
+    pub fn init(config: &Settings) -> Result<Telemetry> {
+        let otel = match config.opentelemetry {
+            Some(ref otel) => Some(otel::Telemetry::new(otel)?),
+            None => None,
+        };
+        Ok(Telemetry { _otel: otel })
+    }
+
+    #[cfg(not(feature = "opentelemetry"))]
+    pub fn init(config: &Settings) -> Result<Telemetry> {
+        if config.opentelemetry.is_some() {
+            log::warn!(
+                "Ignoring [opentelemetry] settings: built without the opentelemetry feature"
+            );
+        }
+        Ok(Telemetry {})
+    }
+}
+
+/// Runs `f` within a span that is linked to the trace propagated in the message's headers.
+#[cfg(feature = "opentelemetry")]
+pub fn in_message_span<M, T, E, F>(name: &'static str, msg: &M, f: F) -> Result<T, E>
+where
+    M: Message<Headers = rdkafka::message::BorrowedHeaders>,
+    E: Display,
+    F: FnOnce() -> Result<T, E>,
+{
+    otel::in_message_span(name, msg, f)
+}
+
+/// Runs `f` within a span that is linked to the trace propagated in the message's headers.
+#[cfg(not(feature = "opentelemetry"))]
+pub fn in_message_span<M, T, E, F>(_name: &'static str, _msg: &M, f: F) -> Result<T, E>
+where
+    M: Message<Headers = rdkafka::message::BorrowedHeaders>,
+    E: Display,
+    F: FnOnce() -> Result<T, E>,
+{
+    f()
+}
+
+/// Runs `f` within a child span of the current span, e.g. of [`in_message_span`].
+#[cfg(feature = "opentelemetry")]
+pub fn in_span<T, E, F>(name: &'static str, f: F) -> Result<T, E>
+where
+    E: Display,
+    F: FnOnce() -> Result<T, E>,
+{
+    otel::in_span(name, f)
+}
+
+/// Runs `f` within a child span of the current span, e.g. of [`in_message_span`].
+#[cfg(not(feature = "opentelemetry"))]
+pub fn in_span<T, E, F>(_name: &'static str, f: F) -> Result<T, E>
+where
+    E: Display,
+    F: FnOnce() -> Result<T, E>,
+{
+    f()
+}
@@ -0,0 +1,522 @@
+/*
+ * Copyright 2021 Michael Krolikowski
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Checks of a configuration going beyond deserialization, e.g. to validate it before a deployment.
+
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::path::Path;
+
+use anyhow::{Result, anyhow};
+use axum::http::Uri;
+use rdkafka::consumer::BaseConsumer;
+
+use crate::consumer::kafka_client_config;
+use crate::metadata::METADATA_CF;
+use crate::offsets::OFFSETS_CF;
+use crate::settings::{
+    ConsumerMode, ObjectStoreSettings, RestoreSource, Settings, SettingsSources,
+};
+
+const MAX_TOPIC_LENGTH: usize = 249;
+
+/// Problems found in a configuration.
+#[derive(Debug, Default)]
+pub struct Validation {
+    pub errors: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+impl Validation {
+    pub fn is_valid(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    fn check(&mut self, result: Result<()>) {
+        if let Err(e) = result {
+            self.errors.push(format!("{e:#}"));
+        }
+    }
+
+    /// Logs the warnings and fails with the errors.
+    fn into_result(self) -> Result<()> {
+        for warning in self.warnings.iter() {
+            log::warn!("{warning}");
+        }
+        if !self.is_valid() {
+            return Err(anyhow!("Invalid configuration: {}", self.errors.join(", ")));
+        }
+        Ok(())
+    }
+}
+
+/// Validates the configuration read from `sources`, including unknown keys and `KR_`
+/// environment variables.
+pub fn validate(sources: &SettingsSources) -> Validation {
+    let mut validation = Validation::default();
+    for filename in sources.files.iter() {
+        if !Path::new(filename).is_file() {
+            validation
+                .errors
+                .push(format!("Configuration file {filename} not found"));
+        }
+    }
+    if !validation.is_valid() {
+        return validation;
+    }
+    match Settings::read_reporting_unknown(sources) {
+        Ok((settings, unknown)) => {
+            for key in unknown {
+                validation.errors.push(format!("Unknown setting {key}"));
+            }
+            validate_settings(&settings, &mut validation);
+        }
+        Err(e) => validation.errors.push(format!("{e:#}")),
+    }
+    validation
+}
+
+/// Fails with the errors found in `settings` and logs its warnings, so that e.g. a missing
+/// `rocksdb.directory` or top-level settings ignored next to `pipelines` stop the startup.
+pub fn check(settings: &Settings) -> Result<()> {
+    let mut validation = Validation::default();
+    validate_settings(settings, &mut validation);
+    validation.into_result()
+}
+
+/// Like [`check`], but only checks the RocksDB settings of a pipeline, for commands which don't
+/// connect to Kafka, e.g. to inspect the database.
+pub fn check_rocksdb(settings: &Settings) -> Result<()> {
+    let mut validation = Validation::default();
+    validate_rocksdb(settings, &mut validation);
+    validation.into_result()
+}
+
+fn validate_settings(settings: &Settings, validation: &mut Validation) {
+    validation.check(
+        settings
+            .prometheus
+            .address
+            .parse::<SocketAddr>()
+            .map(|_| ())
+            .map_err(|e| anyhow!("Invalid prometheus.address: {e}")),
+    );
+    if let Some(ref opentelemetry) = settings.opentelemetry {
+        validation.check(validate_url(
+            "opentelemetry.endpoint",
+            &opentelemetry.endpoint,
+        ));
+        if cfg!(not(feature = "opentelemetry")) {
+            validation
+                .warnings
+                .push("opentelemetry is ignored without the opentelemetry feature".to_string());
+        }
+    }
+    if settings.pipelines.is_empty() {
+        validate_pipeline(settings, validation);
+        return;
+    }
+
+    if !settings.topics.is_empty()
+        || !settings.rocksdb.directory.is_empty()
+        || settings.backup.is_some()
+        || settings.snapshots.is_some()
+    {
+        validation.errors.push(
+            "topics, rocksdb, backup and snapshots must be configured per pipeline".to_string(),
+        );
+    }
+    let mut directories = HashSet::new();
+    let mut snapshot_locations = HashSet::new();
+    for pipeline in settings.pipelines() {
+        let name = &pipeline.pipeline;
+        validation.check(validate_pipeline_name(name));
+        if !directories.insert(pipeline.rocksdb.directory.clone()) {
+            validation.errors.push(format!(
+                "pipelines.{name}.rocksdb.directory is used by another pipeline"
+            ));
+        }
+        // Pipelines sharing a location would remove each other's snapshots.
+        if let Some(ref snapshots) = pipeline.snapshots
+            && !snapshot_locations.insert((
+                snapshots.s3.endpoint.clone(),
+                snapshots.s3.bucket.clone(),
+                snapshots.s3.prefix.trim_matches('/').to_string(),
+            ))
+        {
+            validation.errors.push(format!(
+                "pipelines.{name}.snapshots.s3 bucket and prefix are used by another pipeline"
+            ));
+        }
+        let mut pipeline_validation = Validation::default();
+        validate_pipeline(&pipeline, &mut pipeline_validation);
+        for error in pipeline_validation.errors {
+            validation.errors.push(format!("Pipeline {name}: {error}"));
+        }
+        for warning in pipeline_validation.warnings {
+            validation
+                .warnings
+                .push(format!("Pipeline {name}: {warning}"));
+        }
+    }
+}
+
+/// Checks that `name` can be used in the paths of the HTTP API.
+pub fn validate_pipeline_name(name: &str) -> Result<()> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err(anyhow!(
+            "Pipeline {name:?} may only contain ASCII letters, digits, '_' and '-'"
+        ));
+    }
+    Ok(())
+}
+
+/// Checks the settings of a single pipeline, see [`Settings::pipelines`].
+fn validate_pipeline(settings: &Settings, validation: &mut Validation) {
+    if settings.topics.is_empty() {
+        validation.errors.push("No topics configured".to_string());
+    }
+    let mut topics = HashSet::new();
+    for topic in settings.topics.iter() {
+        validation.check(validate_topic(topic));
+        if !topics.insert(topic) {
+            validation.errors.push(format!("Duplicate topic {topic}"));
+        }
+    }
+    for (topic, partitions) in settings.consumer.partitions.iter() {
+        if !topics.contains(topic) {
+            validation.errors.push(format!(
+                "consumer.partitions.{topic} is not a configured topic"
+            ));
+        }
+        for partition in partitions.keys() {
+            if !partition.parse::<i32>().is_ok_and(|p| p >= 0) {
+                validation.errors.push(format!(
+                    "consumer.partitions.{topic}.{partition} is not a partition"
+                ));
+            }
+        }
+    }
+
+    validate_kafka(settings, validation);
+    validate_rocksdb(settings, validation);
+    if let Some(ref backup) = settings.backup
+        && backup.interval == Some(0)
+    {
+        validation
+            .errors
+            .push("backup.interval must be positive".to_string());
+    }
+    if let Some(ref snapshots) = settings.snapshots {
+        if cfg!(not(feature = "s3")) {
+            validation
+                .errors
+                .push("snapshots require the s3 feature".to_string());
+        }
+        if snapshots.interval == 0 {
+            validation
+                .errors
+                .push("snapshots.interval must be positive".to_string());
+        }
+        validate_object_store("snapshots.s3", &snapshots.s3, validation);
+    }
+}
+
+/// Checks that `topic` is a legal Kafka topic name and doesn't clash with internal column families.
+pub fn validate_topic(topic: &str) -> Result<()> {
+    if topic.is_empty() || topic.len() > MAX_TOPIC_LENGTH {
+        return Err(anyhow!(
+            "Topic {topic:?} must have 1 to {MAX_TOPIC_LENGTH} characters"
+        ));
+    }
+    if topic == "." || topic == ".." {
+        return Err(anyhow!("Topic {topic:?} is not allowed"));
+    }
+    if !topic
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-')
+    {
+        return Err(anyhow!(
+            "Topic {topic:?} may only contain ASCII letters, digits, '.', '_' and '-'"
+        ));
+    }
+    if topic == OFFSETS_CF || topic == METADATA_CF {
+        return Err(anyhow!(
+            "Topic {topic:?} clashes with an internal column family"
+        ));
+    }
+    Ok(())
+}
+
+/// Checks that `servers` is a comma separated list of `host:port`.
+fn validate_bootstrap_servers(servers: &str) -> Result<()> {
+    for server in servers.split(',').map(str::trim) {
+        let address = server.split_once("://").map_or(server, |(_, a)| a);
+        match address.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {}
+            _ => {
+                return Err(anyhow!(
+                    "Invalid bootstrap server {server:?}, expected host:port"
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn validate_kafka(settings: &Settings, validation: &mut Validation) {
+    match settings.kafka.get("bootstrap.servers") {
+        Some(servers) => validation.check(validate_bootstrap_servers(servers)),
+        None => validation
+            .errors
+            .push("kafka.\"bootstrap.servers\" is required".to_string()),
+    }
+    if settings.consumer.mode == ConsumerMode::Group && !settings.kafka.contains_key("group.id") {
+        validation
+            .errors
+            .push("kafka.\"group.id\" is required with consumer.mode = \"group\"".to_string());
+    }
+    // librdkafka validates property names and values when creating a client.
+    if let Err(e) = kafka_client_config(settings).create::<BaseConsumer>() {
+        validation
+            .errors
+            .push(format!("Invalid Kafka configuration: {e}"));
+    }
+}
+
+fn validate_url(name: &str, url: &str) -> Result<()> {
+    match url.parse::<Uri>() {
+        Ok(uri) if uri.scheme().is_some() && uri.host().is_some() => Ok(()),
+        _ => Err(anyhow!("Invalid {name}: {url:?} is not a URL")),
+    }
+}
+
+fn validate_object_store(name: &str, settings: &ObjectStoreSettings, validation: &mut Validation) {
+    if settings.bucket.is_empty() {
+        validation.errors.push(format!("{name}.bucket is empty"));
+    }
+    if let Some(ref endpoint) = settings.endpoint {
+        validation.check(validate_url(&format!("{name}.endpoint"), endpoint));
+    }
+}
+
+fn validate_rocksdb(settings: &Settings, validation: &mut Validation) {
+    if settings.rocksdb.directory.is_empty() {
+        validation
+            .errors
+            .push("rocksdb.directory is required".to_string());
+        return;
+    }
+    let directory = Path::new(&settings.rocksdb.directory);
+    if directory.exists() {
+        if !directory.is_dir() {
+            validation.errors.push(format!(
+                "rocksdb.directory {} is not a directory",
+                directory.display()
+            ));
+        } else if directory.join("CURRENT").exists()
+            && let Err(e) = rocksdb::DB::list_cf(&rocksdb::Options::default(), directory)
+        {
+            validation.errors.push(format!(
+                "rocksdb.directory {} can't be read: {e}",
+                directory.display()
+            ));
+        }
+    } else if directory
+        .parent()
+        .is_some_and(|parent| !parent.as_os_str().is_empty() && !parent.exists())
+    {
+        validation.errors.push(format!(
+            "Parent directory of rocksdb.directory {} doesn't exist",
+            directory.display()
+        ));
+    }
+
+    let restore = match settings.rocksdb.restore {
+        Some(ref restore) => restore,
+        None => return,
+    };
+    match restore.source {
+        RestoreSource::Checkpoint | RestoreSource::Backup => match restore.directory {
+            Some(ref source) if !Path::new(source).exists() => validation.warnings.push(format!(
+                "rocksdb.restore.directory {source} doesn't exist, so nothing will be restored"
+            )),
+            Some(_) => {}
+            None => validation
+                .errors
+                .push("rocksdb.restore.directory is required".to_string()),
+        },
+        RestoreSource::S3 => {
+            if cfg!(not(feature = "s3")) {
+                validation
+                    .errors
+                    .push("Restoring from S3 requires the s3 feature".to_string());
+            }
+            match restore.s3 {
+                Some(ref s3) => validate_object_store("rocksdb.restore.s3", s3, validation),
+                None => validation
+                    .errors
+                    .push("rocksdb.restore.s3 is required".to_string()),
+            }
+        }
+    }
+}
+
+/// JSON Schema of the configuration, e.g. for completion in editors.
+pub fn json_schema() -> Result<String> {
+    Ok(serde_json::to_string_pretty(&schemars::schema_for!(
+        Settings
+    ))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_topics() {
+        assert!(validate_topic("orders.v1_EU-west").is_ok());
+        assert!(validate_topic("").is_err());
+        assert!(validate_topic("..").is_err());
+        assert!(validate_topic("orders@1").is_err());
+        assert!(validate_topic(&"a".repeat(MAX_TOPIC_LENGTH + 1)).is_err());
+        assert!(validate_topic(OFFSETS_CF).is_err());
+    }
+
+    #[test]
+    fn validates_bootstrap_servers() {
+        assert!(validate_bootstrap_servers("localhost:9092, SSL://kafka-1:9093").is_ok());
+        assert!(validate_bootstrap_servers("localhost").is_err());
+        assert!(validate_bootstrap_servers("localhost:port").is_err());
+        assert!(validate_bootstrap_servers(":9092").is_err());
+    }
+
+    #[test]
+    fn reports_all_problems() -> Result<()> {
+        let directory = tempfile::tempdir()?;
+        let config = directory.path().join("config.toml");
+        std::fs::write(
+            &config,
+            r#"
+topics = ["test", "test", "__offsets"]
+
+[kafka]
+"bootstrap.servers" = "localhost:9092"
+"group.id" = "kafka_rocksdb"
+"fetch.wait.max.ms" = "forever"
+
+[rocksdb]
+"directory" = "/does/not/exist/db"
+"restor" = { source = "checkpoint" }
+
+[prometheus]
+"address" = "localhost"
+"#,
+        )?;
+        let validation = validate(&SettingsSources::file(&config.to_string_lossy()));
+        assert_eq!(validation.errors.len(), 6, "{:?}", validation.errors);
+        assert!(
+            validation
+                .errors
+                .contains(&"Unknown setting rocksdb.restor".to_string())
+        );
+        assert!(
+            validation
+                .errors
+                .contains(&"Duplicate topic test".to_string())
+        );
+
+        let missing = directory.path().join("missing.toml");
+        assert!(!validate(&SettingsSources::file(&missing.to_string_lossy())).is_valid());
+        Ok(())
+    }
+
+    #[test]
+    fn validates_pipelines() -> Result<()> {
+        let directory = tempfile::tempdir()?;
+        let config = directory.path().join("config.toml");
+        std::fs::write(
+            &config,
+            r#"
+topics = ["test"]
+
+[kafka]
+"bootstrap.servers" = "localhost:9092"
+"group.id" = "kafka_rocksdb"
+
+[prometheus]
+"address" = "127.0.0.1:0"
+
+[pipelines.orders]
+topics = ["orders"]
+rocksdb = { directory = "db" }
+
+[pipelines."users/v2"]
+topics = ["users"]
+rocksdb = { directory = "db" }
+"#,
+        )?;
+        let validation = validate(&SettingsSources::file(&config.to_string_lossy()));
+        assert_eq!(validation.errors.len(), 3, "{:?}", validation.errors);
+        assert!(validation.errors.contains(
+            &"pipelines.users/v2.rocksdb.directory is used by another pipeline".to_string()
+        ));
+        assert!(validate_pipeline_name("orders-eu_1").is_ok());
+        assert!(validate_pipeline_name("").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_shared_snapshot_locations() -> Result<()> {
+        let directory = tempfile::tempdir()?;
+        let config = directory.path().join("config.toml");
+        std::fs::write(
+            &config,
+            r#"
+[kafka]
+"bootstrap.servers" = "localhost:9092"
+"group.id" = "kafka_rocksdb"
+
+[prometheus]
+"address" = "127.0.0.1:0"
+
+[pipelines.orders]
+topics = ["orders"]
+rocksdb = { directory = "orders" }
+snapshots = { interval = 60, s3 = { bucket = "snapshots", prefix = "db" } }
+
+[pipelines.users]
+topics = ["users"]
+rocksdb = { directory = "users" }
+snapshots = { interval = 60, s3 = { bucket = "snapshots", prefix = "/db/" } }
+"#,
+        )?;
+        let validation = validate(&SettingsSources::file(&config.to_string_lossy()));
+        assert!(
+            validation.errors.contains(
+                &"pipelines.users.snapshots.s3 bucket and prefix are used by another pipeline"
+                    .to_string()
+            ),
+            "{:?}",
+            validation.errors
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,213 @@
+/*
+ * Copyright 2021 Michael Krolikowski
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Verifies a store against its topic: the topic is read from the beginning up to the offsets
+//! stored with the data, and the last value of every key is compared to the stored record.
+//!
+//! The store is expected to have consumed the topic from the earliest offset, otherwise keys
+//! written before its start position are reported as missing.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use anyhow::{Result, anyhow};
+use rdkafka::consumer::{BaseConsumer, Consumer};
+use rdkafka::error::KafkaError;
+use rdkafka::{Message, Offset, TopicPartitionList};
+use serde::Serialize;
+
+use crate::consumer::kafka_client_config;
+use crate::encoding::hex;
+use crate::kafka_context::KafkaRocksDBContext;
+use crate::offsets::Offsets;
+use crate::settings::Settings;
+use crate::store::Store;
+
+const POLL_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Difference {
+    /// Key, hex encoded.
+    pub key: String,
+    /// Partition of the last message with this key, if any.
+    pub partition: Option<i32>,
+    /// Offset of the last message with this key, if any.
+    pub offset: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub topic: String,
+    /// Offsets of the store, up to which the topic was read.
+    pub offsets: Offsets,
+    /// Number of messages read from the topic.
+    pub messages: u64,
+    /// Number of records in the store.
+    pub records: u64,
+    /// Keys whose last message has a value, but which are not in the store.
+    pub missing: Vec<Difference>,
+    /// Keys in the store which were never written or deleted by a tombstone.
+    pub extra: Vec<Difference>,
+    /// Keys whose stored value differs from the value of their last message.
+    pub mismatching: Vec<Difference>,
+}
+
+impl Report {
+    pub fn is_consistent(&self) -> bool {
+        self.missing.is_empty() && self.extra.is_empty() && self.mismatching.is_empty()
+    }
+}
+
+/// The last message of a key.
+struct Last {
+    value: Option<Vec<u8>>,
+    partition: i32,
+    offset: i64,
+}
+
+impl Last {
+    fn difference(&self, key: &[u8]) -> Difference {
+        Difference {
+            key: hex(key),
+            partition: Some(self.partition),
+            offset: Some(self.offset),
+        }
+    }
+}
+
+/// Compares the records of `topic` in `store` to the last values of their keys in Kafka.
+pub fn verify(settings: &Settings, topic: &str, store: &impl Store) -> Result<Report> {
+    let offsets = store.offsets()?.topic(topic);
+    let (messages, mut expected) = read_topic(settings, topic, &offsets)?;
+    let mut report = Report {
+        topic: topic.to_string(),
+        offsets,
+        messages,
+        records: 0,
+        missing: vec![],
+        extra: vec![],
+        mismatching: vec![],
+    };
+    store.for_each(topic, b"", &mut |key, value| {
+        report.records += 1;
+        match expected.remove(key) {
+            Some(last) if last.value.as_deref() == Some(value) => {}
+            Some(last) if last.value.is_some() => report.mismatching.push(last.difference(key)),
+            Some(last) => report.extra.push(last.difference(key)),
+            None => report.extra.push(Difference {
+                key: hex(key),
+                partition: None,
+                offset: None,
+            }),
+        }
+        Ok(())
+    })?;
+    report.missing = expected
+        .iter()
+        .filter(|(_, last)| last.value.is_some())
+        .map(|(key, last)| last.difference(key))
+        .collect();
+    report.missing.sort_by(|a, b| a.key.cmp(&b.key));
+    Ok(report)
+}
+
+/// Reads `topic` from the beginning up to `offsets`, returning the number of messages and the
+/// last message per key.
+fn read_topic(
+    settings: &Settings,
+    topic: &str,
+    offsets: &Offsets,
+) -> Result<(u64, HashMap<Vec<u8>, Last>)> {
+    let mut client_config = kafka_client_config(settings);
+    let group_id = settings
+        .kafka
+        .get("group.id")
+        .map(String::as_str)
+        .unwrap_or("kafka_rocksdb");
+    client_config.set("group.id", format!("{group_id}-verify"));
+    client_config.set("enable.auto.commit", "false");
+    client_config.set("enable.partition.eof", "true");
+    client_config.set("auto.offset.reset", "earliest");
+    let consumer: BaseConsumer<KafkaRocksDBContext> =
+        client_config.create_with_context(KafkaRocksDBContext::new(&settings.librdkafka))?;
+
+    let mut tpl = TopicPartitionList::new();
+    let mut positions = HashMap::new();
+    let mut targets = HashMap::new();
+    for (_, partition, offset) in offsets.iter().filter(|(_, _, offset)| *offset > 0) {
+        tpl.add_partition_offset(topic, partition, Offset::Beginning)?;
+        positions.insert(partition, 0);
+        targets.insert(partition, offset);
+    }
+    let mut messages = 0;
+    let mut expected = HashMap::new();
+    if targets.is_empty() {
+        return Ok((messages, expected));
+    }
+    consumer.assign(&tpl)?;
+
+    while targets
+        .iter()
+        .any(|(partition, target)| positions[partition] < *target)
+    {
+        match consumer.poll(POLL_TIMEOUT) {
+            Some(Ok(msg)) => {
+                let partition = msg.partition();
+                if msg.offset() < targets[&partition] {
+                    messages += 1;
+                    if let Some(key) = msg.key() {
+                        let last = Last {
+                            value: msg.payload().map(<[u8]>::to_vec),
+                            partition,
+                            offset: msg.offset(),
+                        };
+                        expected.insert(key.to_vec(), last);
+                    }
+                }
+                positions.insert(partition, msg.offset() + 1);
+            }
+            Some(Err(KafkaError::PartitionEOF(partition))) => {
+                update_positions(&consumer, topic, &mut positions)?;
+                if positions[&partition] < targets[&partition] {
+                    return Err(anyhow!(
+                        "Partition {partition} of {topic} ends at {}, before the stored offset {}",
+                        positions[&partition],
+                        targets[&partition]
+                    ));
+                }
+            }
+            // Compacted or aborted messages leave gaps, so rely on the consumer's position.
+            None => update_positions(&consumer, topic, &mut positions)?,
+            Some(Err(e)) => return Err(e.into()),
+        }
+    }
+    Ok((messages, expected))
+}
+
+fn update_positions(
+    consumer: &BaseConsumer<KafkaRocksDBContext>,
+    topic: &str,
+    positions: &mut HashMap<i32, i64>,
+) -> Result<()> {
+    for elem in consumer.position()?.elements_for_topic(topic) {
+        if let Offset::Offset(offset) = elem.offset()
+            && let Some(position) = positions.get_mut(&elem.partition())
+        {
+            *position = (*position).max(offset);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,120 @@
+/*
+ * Copyright 2021 Michael Krolikowski
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Fixture running the materializer against librdkafka's in-process mock cluster.
+
+#![allow(dead_code)]
+
+use std::time::Duration;
+
+use anyhow::Result;
+use kafka_rocksdb::kafka_rocksdb::KafkaRocksDB;
+use kafka_rocksdb::settings::Settings;
+use rdkafka::ClientConfig;
+use rdkafka::mocking::MockCluster;
+use rdkafka::producer::{DefaultProducerContext, FutureProducer, FutureRecord};
+use tempfile::TempDir;
+
+pub const TOPIC: &str = "test";
+pub const GROUP: &str = "kafka_rocksdb_test";
+pub const TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct Fixture {
+    producer: FutureProducer,
+    pub cluster: MockCluster<'static, DefaultProducerContext>,
+    directory: TempDir,
+}
+
+impl Fixture {
+    pub fn new(partitions: i32) -> Result<Fixture> {
+        let cluster = MockCluster::new(1)?;
+        cluster.create_topic(TOPIC, partitions, 1)?;
+        let producer = ClientConfig::new()
+            .set("bootstrap.servers", cluster.bootstrap_servers())
+            .create()?;
+        Ok(Fixture {
+            producer,
+            cluster,
+            directory: tempfile::tempdir()?,
+        })
+    }
+
+    pub async fn produce(
+        &self,
+        partition: i32,
+        key: Option<&str>,
+        value: Option<&str>,
+    ) -> Result<()> {
+        self.produce_to(TOPIC, partition, key, value).await
+    }
+
+    pub async fn produce_to(
+        &self,
+        topic: &str,
+        partition: i32,
+        key: Option<&str>,
+        value: Option<&str>,
+    ) -> Result<()> {
+        let mut record = FutureRecord::<str, str>::to(topic).partition(partition);
+        if let Some(key) = key {
+            record = record.key(key);
+        }
+        if let Some(value) = value {
+            record = record.payload(value);
+        }
+        self.producer
+            .send(record, Duration::from_secs(5))
+            .await
+            .map_err(|(e, _)| e)?;
+        Ok(())
+    }
+
+    /// Settings consuming until the end of the topic, extended by `extra` TOML.
+    pub fn settings(&self, extra: &str) -> Result<Settings> {
+        let config = self.directory.path().join("config.toml");
+        std::fs::write(
+            &config,
+            format!(
+                r#"
+topics = ["{TOPIC}"]
+
+[kafka]
+"bootstrap.servers" = "{}"
+"group.id" = "{GROUP}"
+
+[rocksdb]
+"directory" = "{}"
+
+[prometheus]
+"address" = "127.0.0.1:0"
+
+[consumer]
+"until_end" = true
+{extra}
+"#,
+                self.cluster.bootstrap_servers(),
+                self.directory.path().join("db").display(),
+            ),
+        )?;
+        Settings::read(&config.to_string_lossy())
+    }
+
+    pub async fn run(&self, settings: &Settings) -> Result<KafkaRocksDB> {
+        let kafka_rocksdb = KafkaRocksDB::new(settings)?;
+        tokio::time::timeout(TIMEOUT, kafka_rocksdb.start()).await??;
+        Ok(kafka_rocksdb)
+    }
+}
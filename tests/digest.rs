@@ -0,0 +1,89 @@
+/*
+ * Copyright 2021 Michael Krolikowski
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Digests of key ranges.
+
+use anyhow::Result;
+use kafka_rocksdb::database::Database;
+use kafka_rocksdb::digest::digest;
+use kafka_rocksdb::settings::Settings;
+use tempfile::TempDir;
+
+const TOPIC: &str = "test";
+
+/// Database storing `TOPIC` in `directory`, without any Kafka cluster.
+fn database(directory: &TempDir) -> Result<Database> {
+    let config = directory.path().join("config.toml");
+    std::fs::write(
+        &config,
+        format!(
+            r#"
+topics = ["{TOPIC}"]
+
+[kafka]
+"bootstrap.servers" = "localhost:9092"
+
+[rocksdb]
+"directory" = "{}"
+
+[prometheus]
+"address" = "127.0.0.1:0"
+"#,
+            directory.path().join("db").display()
+        ),
+    )?;
+    Database::new(&Settings::read(&config.to_string_lossy())?)
+}
+
+#[test]
+fn digests_are_consistent_across_levels() -> Result<()> {
+    let directory = tempfile::tempdir()?;
+    let db = database(&directory)?;
+    let cf = db.column_family(TOPIC)?;
+    for key in ["a", "a1", "a2", "b1"] {
+        db.put_column_family(&cf, key.as_bytes(), Some(b"value".as_slice()))?;
+    }
+
+    let root = digest(&db.snapshot(), TOPIC, b"")?;
+    assert_eq!(root.range.records, 4);
+    let prefixes: Vec<&str> = root.children.iter().map(|c| c.prefix.as_str()).collect();
+    assert_eq!(prefixes, vec!["61", "62"]);
+
+    let a = digest(&db.snapshot(), TOPIC, b"a")?;
+    assert_eq!(a.range, root.children[0]);
+    assert_eq!(a.range.records, 3);
+    // "a" itself is only part of the range, not of a child.
+    assert_eq!(a.children.len(), 2);
+    Ok(())
+}
+
+#[test]
+fn changes_are_located_in_their_range() -> Result<()> {
+    let directory = tempfile::tempdir()?;
+    let db = database(&directory)?;
+    let cf = db.column_family(TOPIC)?;
+    for key in ["a1", "a2", "b1"] {
+        db.put_column_family(&cf, key.as_bytes(), Some(b"value".as_slice()))?;
+    }
+    let before = digest(&db.snapshot(), TOPIC, b"")?;
+
+    db.put_column_family(&cf, b"b1", Some(b"changed".as_slice()))?;
+    let after = digest(&db.snapshot(), TOPIC, b"")?;
+    assert_ne!(before.range.digest, after.range.digest);
+    assert_eq!(before.children[0], after.children[0]);
+    assert_ne!(before.children[1], after.children[1]);
+    Ok(())
+}
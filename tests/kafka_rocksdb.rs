@@ -0,0 +1,140 @@
+/*
+ * Copyright 2021 Michael Krolikowski
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Runs the materializer against librdkafka's in-process mock cluster.
+
+mod common;
+
+use anyhow::Result;
+use kafka_rocksdb::kafka_rocksdb::KafkaRocksDB;
+use kafka_rocksdb::rebuild::Rebuilds;
+use rdkafka::consumer::{BaseConsumer, Consumer};
+use rdkafka::{ClientConfig, Offset, TopicPartitionList};
+
+use common::{Fixture, GROUP, TIMEOUT, TOPIC};
+
+fn committed(fixture: &Fixture, partitions: i32) -> Result<Vec<Offset>> {
+    let consumer: BaseConsumer = ClientConfig::new()
+        .set("bootstrap.servers", fixture.cluster.bootstrap_servers())
+        .set("group.id", GROUP)
+        .create()?;
+    let mut tpl = TopicPartitionList::new();
+    for partition in 0..partitions {
+        tpl.add_partition(TOPIC, partition);
+    }
+    Ok(consumer
+        .committed_offsets(tpl, TIMEOUT)?
+        .elements()
+        .iter()
+        .map(|e| e.offset())
+        .collect())
+}
+
+fn get(kafka_rocksdb: &KafkaRocksDB, key: &str) -> Result<Option<String>> {
+    let db = kafka_rocksdb.database();
+    let value = db.snapshot().multi_get(TOPIC, [key])?.remove(0);
+    Ok(value.map(|v| String::from_utf8_lossy(&v).to_string()))
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn materializes_records_and_tombstones() -> Result<()> {
+    let fixture = Fixture::new(2)?;
+    fixture.produce(0, Some("a"), Some("1")).await?;
+    fixture.produce(1, Some("b"), Some("2")).await?;
+    fixture.produce(0, Some("a"), Some("3")).await?;
+    fixture.produce(1, Some("b"), None).await?;
+    fixture.produce(0, None, Some("ignored")).await?;
+
+    let kafka_rocksdb = fixture.run(&fixture.settings("")?).await?;
+    assert_eq!(get(&kafka_rocksdb, "a")?, Some("3".to_string()));
+    assert_eq!(get(&kafka_rocksdb, "b")?, None);
+    let offsets = kafka_rocksdb.database().offsets()?;
+    assert_eq!(offsets.get(TOPIC, 0), Some(3));
+    assert_eq!(offsets.get(TOPIC, 1), Some(2));
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn commits_offsets_on_shutdown() -> Result<()> {
+    let fixture = Fixture::new(2)?;
+    fixture.produce(0, Some("a"), Some("1")).await?;
+    fixture.produce(0, Some("b"), Some("2")).await?;
+    fixture.produce(1, Some("c"), Some("3")).await?;
+
+    drop(fixture.run(&fixture.settings("")?).await?);
+    assert_eq!(
+        committed(&fixture, 2)?,
+        vec![Offset::Offset(2), Offset::Offset(1)]
+    );
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn resumes_after_restart() -> Result<()> {
+    let fixture = Fixture::new(1)?;
+    let settings = fixture.settings("")?;
+    fixture.produce(0, Some("a"), Some("1")).await?;
+    drop(fixture.run(&settings).await?);
+
+    fixture.produce(0, Some("a"), None).await?;
+    fixture.produce(0, Some("b"), Some("2")).await?;
+    let kafka_rocksdb = fixture.run(&settings).await?;
+    assert_eq!(get(&kafka_rocksdb, "a")?, None);
+    assert_eq!(get(&kafka_rocksdb, "b")?, Some("2".to_string()));
+    assert_eq!(kafka_rocksdb.database().offsets()?.get(TOPIC, 0), Some(3));
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn assign_mode_starts_at_configured_offset() -> Result<()> {
+    let fixture = Fixture::new(1)?;
+    fixture.produce(0, Some("a"), Some("1")).await?;
+    fixture.produce(0, Some("b"), Some("2")).await?;
+
+    let settings = fixture.settings(&format!(
+        "\"mode\" = \"assign\"\n[consumer.partitions.{TOPIC}]\n\"0\" = {{ offset = 1 }}"
+    ))?;
+    let kafka_rocksdb = fixture.run(&settings).await?;
+    assert_eq!(get(&kafka_rocksdb, "a")?, None);
+    assert_eq!(get(&kafka_rocksdb, "b")?, Some("2".to_string()));
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn stops_immediately_on_empty_topics() -> Result<()> {
+    let fixture = Fixture::new(2)?;
+    let kafka_rocksdb = fixture.run(&fixture.settings("")?).await?;
+    assert!(kafka_rocksdb.database().offsets()?.is_empty());
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn rebuilds_until_end_pipelines() -> Result<()> {
+    let fixture = Fixture::new(2)?;
+    fixture.produce(0, Some("a"), Some("1")).await?;
+    fixture.produce(1, Some("b"), Some("2")).await?;
+
+    let settings = fixture.settings("")?;
+    let kafka_rocksdb = fixture.run(&settings).await?;
+    let db = kafka_rocksdb.database();
+    let column_family = db.column_family(TOPIC)?;
+    let rebuilds = Rebuilds::new(db.clone(), &settings);
+    tokio::time::timeout(TIMEOUT, rebuilds.rebuild(TOPIC)).await??;
+    assert_ne!(db.column_family(TOPIC)?, column_family);
+    assert_eq!(get(&kafka_rocksdb, "a")?, Some("1".to_string()));
+    assert_eq!(get(&kafka_rocksdb, "b")?, Some("2".to_string()));
+    Ok(())
+}
@@ -0,0 +1,100 @@
+/*
+ * Copyright 2021 Michael Krolikowski
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Several pipelines consuming into their own databases within one process.
+
+mod common;
+
+use anyhow::Result;
+use kafka_rocksdb::kafka_rocksdb::KafkaRocksDB;
+use kafka_rocksdb::metrics::MESSAGES;
+use kafka_rocksdb::settings::Settings;
+
+use common::{Fixture, GROUP, TIMEOUT, TOPIC};
+
+const OTHER: &str = "other";
+
+fn get(kafka_rocksdb: &KafkaRocksDB, topic: &str, key: &str) -> Result<Option<Vec<u8>>> {
+    Ok(kafka_rocksdb
+        .database()
+        .snapshot()
+        .multi_get(topic, [key])?
+        .remove(0))
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn runs_independent_pipelines() -> Result<()> {
+    let fixture = Fixture::new(1)?;
+    fixture.cluster.create_topic(OTHER, 1, 1)?;
+    fixture.produce(0, Some("a"), Some("1")).await?;
+    fixture.produce_to(OTHER, 0, Some("b"), Some("2")).await?;
+    fixture.produce_to(OTHER, 0, Some("c"), Some("3")).await?;
+
+    let directory = tempfile::tempdir()?;
+    let config = directory.path().join("config.toml");
+    std::fs::write(
+        &config,
+        format!(
+            r#"
+[kafka]
+"bootstrap.servers" = "{}"
+"group.id" = "{GROUP}"
+
+[consumer]
+"mode" = "assign"
+"until_end" = true
+
+[prometheus]
+"address" = "127.0.0.1:0"
+
+[pipelines.first]
+topics = ["{TOPIC}"]
+rocksdb = {{ directory = "{}" }}
+
+[pipelines.second]
+topics = ["{OTHER}"]
+kafka = {{ "group.id" = "{GROUP}-second" }}
+rocksdb = {{ directory = "{}" }}
+"#,
+            fixture.cluster.bootstrap_servers(),
+            directory.path().join("first").display(),
+            directory.path().join("second").display(),
+        ),
+    )?;
+    let settings = Settings::read(&config.to_string_lossy())?;
+    let pipelines = settings
+        .pipelines()
+        .iter()
+        .map(KafkaRocksDB::new)
+        .collect::<Result<Vec<_>>>()?;
+    tokio::time::timeout(
+        TIMEOUT,
+        futures::future::try_join_all(pipelines.iter().map(|p| p.start())),
+    )
+    .await??;
+
+    let (first, second) = (&pipelines[0], &pipelines[1]);
+    assert_eq!(first.pipeline(), "first");
+    assert_eq!(get(first, TOPIC, "a")?, Some(b"1".to_vec()));
+    assert!(!first.database().has_topic(OTHER));
+    assert_eq!(second.pipeline(), "second");
+    assert_eq!(get(second, OTHER, "c")?, Some(b"3".to_vec()));
+    assert!(!second.database().has_topic(TOPIC));
+
+    assert_eq!(MESSAGES.with_label_values(&["first"]).get(), 1);
+    assert_eq!(MESSAGES.with_label_values(&["second"]).get(), 2);
+    Ok(())
+}
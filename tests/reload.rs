@@ -0,0 +1,109 @@
+/*
+ * Copyright 2021 Michael Krolikowski
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Adds and removes topics of a running pipeline, as done when reloading the configuration.
+
+mod common;
+
+use std::time::Duration;
+
+use anyhow::{Result, anyhow};
+use kafka_rocksdb::kafka_rocksdb::KafkaRocksDB;
+
+use common::{Fixture, TIMEOUT, TOPIC};
+
+const OTHER: &str = "other";
+
+async fn wait_for(kafka_rocksdb: &KafkaRocksDB, topic: &str, key: &str) -> Result<Vec<u8>> {
+    let db = kafka_rocksdb.database();
+    tokio::time::timeout(TIMEOUT, async {
+        loop {
+            if let Some(value) = db.snapshot().multi_get(topic, [key])?.remove(0) {
+                return Ok::<_, anyhow::Error>(value);
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await?
+}
+
+/// Runs `steps` while consuming, on the same task as `main` runs reloads next to the pipelines.
+async fn while_consuming(
+    kafka_rocksdb: &KafkaRocksDB,
+    steps: impl Future<Output = Result<()>>,
+) -> Result<()> {
+    tokio::select! {
+        result = kafka_rocksdb.start() => result.and(Err(anyhow!("Stopped consuming"))),
+        result = steps => result,
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn adds_and_removes_topics() -> Result<()> {
+    let fixture = Fixture::new(1)?;
+    fixture.cluster.create_topic(OTHER, 2, 1)?;
+    let mut settings = fixture.settings("\"mode\" = \"assign\"")?;
+    settings.consumer.until_end = false;
+    let kafka_rocksdb = KafkaRocksDB::new(&settings)?;
+    while_consuming(&kafka_rocksdb, async {
+        fixture.produce(0, Some("a"), Some("1")).await?;
+        wait_for(&kafka_rocksdb, TOPIC, "a").await?;
+
+        fixture.produce_to(OTHER, 1, Some("b"), Some("2")).await?;
+        settings.topics.push(OTHER.to_string());
+        kafka_rocksdb.update_topics(&settings, &[OTHER.to_string()], &[], false)?;
+        assert_eq!(wait_for(&kafka_rocksdb, OTHER, "b").await?, b"2");
+
+        settings.topics.retain(|t| t != OTHER);
+        kafka_rocksdb.update_topics(&settings, &[], &[OTHER.to_string()], true)?;
+        let db = kafka_rocksdb.database();
+        assert!(!db.has_topic(OTHER));
+        assert_eq!(db.offsets()?.topic(OTHER), Default::default());
+
+        fixture.produce(0, Some("c"), Some("3")).await?;
+        wait_for(&kafka_rocksdb, TOPIC, "c").await?;
+        Ok(())
+    })
+    .await
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn re_adds_dropped_topics_from_the_start() -> Result<()> {
+    let fixture = Fixture::new(1)?;
+    fixture.cluster.create_topic(OTHER, 1, 1)?;
+    let mut settings = fixture.settings("")?;
+    settings.consumer.until_end = false;
+    settings.topics.push(OTHER.to_string());
+    let kafka_rocksdb = KafkaRocksDB::new(&settings)?;
+    while_consuming(&kafka_rocksdb, async {
+        fixture.produce_to(OTHER, 0, Some("a"), Some("1")).await?;
+        wait_for(&kafka_rocksdb, OTHER, "a").await?;
+
+        settings.topics.retain(|t| t != OTHER);
+        kafka_rocksdb.update_topics(&settings, &[], &[OTHER.to_string()], true)?;
+        // Skipped while the topic is removed, but must not be lost.
+        fixture.produce_to(OTHER, 0, Some("b"), Some("2")).await?;
+        fixture.produce(0, Some("c"), Some("3")).await?;
+        wait_for(&kafka_rocksdb, TOPIC, "c").await?;
+
+        settings.topics.push(OTHER.to_string());
+        kafka_rocksdb.update_topics(&settings, &[OTHER.to_string()], &[], false)?;
+        assert_eq!(wait_for(&kafka_rocksdb, OTHER, "a").await?, b"1");
+        assert_eq!(wait_for(&kafka_rocksdb, OTHER, "b").await?, b"2");
+        Ok(())
+    })
+    .await
+}
@@ -0,0 +1,120 @@
+/*
+ * Copyright 2021 Michael Krolikowski
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Connects to a local TLS listener using a CA certificate and a SASL password read from a file.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use kafka_rocksdb::consumer::kafka_client_config;
+use kafka_rocksdb::settings::Settings;
+use rdkafka::consumer::{BaseConsumer, Consumer};
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::crypto::ring::default_provider;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::{AlertDescription, Error as TlsError, ServerConfig};
+
+const TLS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/tls");
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+fn acceptor() -> Result<TlsAcceptor> {
+    let certificates = CertificateDer::pem_file_iter(format!("{TLS}/server.pem"))?
+        .collect::<Result<Vec<_>, _>>()?;
+    let key = PrivateKeyDer::from_pem_file(format!("{TLS}/server.key"))?;
+    let config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certificates, key)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Lets a consumer trusting the CA certificate `ca` connect to a TLS listener and returns the
+/// size of the first Kafka request it sent after the handshake.
+async fn connect(ca: &str) -> Result<i32> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+    let directory = tempfile::tempdir()?;
+    let password = directory.path().join("password");
+    std::fs::write(&password, "secret\n")?;
+    let config = directory.path().join("config.toml");
+    std::fs::write(
+        &config,
+        format!(
+            r#"
+topics = ["test"]
+
+[kafka]
+"bootstrap.servers" = "localhost:{port}"
+"group.id" = "kafka_rocksdb_test"
+"security.protocol" = "sasl_ssl"
+"sasl.mechanism" = "PLAIN"
+"sasl.username" = "kafka-rocksdb"
+"sasl.password" = "${{file:{}}}"
+"ssl.ca.location" = "{TLS}/{ca}"
+
+[rocksdb]
+"directory" = "{}"
+
+[prometheus]
+"address" = "127.0.0.1:0"
+"#,
+            password.display(),
+            directory.path().join("db").display(),
+        ),
+    )?;
+    let settings = Settings::read(&config.to_string_lossy())?;
+    assert_eq!(
+        settings.kafka.get("sasl.password").map(String::as_str),
+        Some("secret")
+    );
+
+    let consumer: BaseConsumer = kafka_client_config(&settings).create()?;
+    // The listener doesn't speak Kafka, so the client just gives up after the timeout.
+    std::thread::spawn(move || consumer.fetch_metadata(None, TIMEOUT));
+    let (stream, _) = tokio::time::timeout(TIMEOUT, listener.accept()).await??;
+    let mut stream = tokio::time::timeout(TIMEOUT, acceptor()?.accept(stream)).await??;
+    Ok(stream.read_i32().await?)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn connects_to_trusted_listener() -> Result<()> {
+    assert!(connect("ca.pem").await? > 0);
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn rejects_untrusted_listener() -> Result<()> {
+    let error = connect("other-ca.pem")
+        .await
+        .expect_err("Connected to an untrusted listener");
+    // The client aborts the handshake as it can't verify the server certificate.
+    let tls_error = error
+        .downcast_ref::<std::io::Error>()
+        .and_then(|e| e.get_ref())
+        .and_then(|e| e.downcast_ref::<TlsError>());
+    assert!(
+        matches!(
+            tls_error,
+            Some(TlsError::AlertReceived(AlertDescription::UnknownCA))
+        ),
+        "{error:?}"
+    );
+    Ok(())
+}
@@ -0,0 +1,92 @@
+/*
+ * Copyright 2021 Michael Krolikowski
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Uses the consistency checker as oracle for the materializer.
+
+mod common;
+
+use anyhow::Result;
+use kafka_rocksdb::verify::{Difference, verify};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use common::{Fixture, TOPIC};
+
+const PARTITIONS: i32 = 3;
+const MESSAGES: u64 = 200;
+const KEYS: u32 = 20;
+
+fn difference(key: &str, position: Option<(i32, i64)>) -> Difference {
+    Difference {
+        key: kafka_rocksdb::encoding::hex(key.as_bytes()),
+        partition: position.map(|(partition, _)| partition),
+        offset: position.map(|(_, offset)| offset),
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn random_topics_are_materialized_consistently() -> Result<()> {
+    for seed in 0..5 {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let fixture = Fixture::new(PARTITIONS)?;
+        for i in 0..MESSAGES {
+            let key = format!("key-{}", rng.random_range(0..KEYS));
+            let key = (!rng.random_bool(0.05)).then_some(key.as_str());
+            let value = format!("value-{i}");
+            let value = (!rng.random_bool(0.2)).then_some(value.as_str());
+            fixture
+                .produce(rng.random_range(0..PARTITIONS), key, value)
+                .await?;
+        }
+
+        let settings = fixture.settings("")?;
+        let kafka_rocksdb = fixture.run(&settings).await?;
+        let db = kafka_rocksdb.database();
+        let report = verify(&settings, TOPIC, &db.snapshot())?;
+        assert_eq!(report.messages, MESSAGES, "seed {seed}");
+        assert!(report.is_consistent(), "seed {seed}: {report:?}");
+    }
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn reports_differences() -> Result<()> {
+    let fixture = Fixture::new(1)?;
+    fixture.produce(0, Some("a"), Some("1")).await?;
+    fixture.produce(0, Some("b"), Some("2")).await?;
+    fixture.produce(0, Some("c"), Some("3")).await?;
+    fixture.produce(0, Some("c"), None).await?;
+
+    let settings = fixture.settings("")?;
+    let kafka_rocksdb = fixture.run(&settings).await?;
+    let db = kafka_rocksdb.database();
+    let cf = db.column_family(TOPIC)?;
+    db.put_column_family(&cf, b"a", Some(b"x".as_slice()))?;
+    db.put_column_family(&cf, b"b", None)?;
+    db.put_column_family(&cf, b"c", Some(b"3".as_slice()))?;
+    db.put_column_family(&cf, b"d", Some(b"4".as_slice()))?;
+
+    let report = verify(&settings, TOPIC, &db.snapshot())?;
+    assert_eq!(report.messages, 4);
+    assert_eq!(report.records, 3);
+    assert_eq!(report.mismatching, vec![difference("a", Some((0, 0)))]);
+    assert_eq!(report.missing, vec![difference("b", Some((0, 1)))]);
+    assert_eq!(
+        report.extra,
+        vec![difference("c", Some((0, 3))), difference("d", None)]
+    );
+    Ok(())
+}